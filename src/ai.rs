@@ -0,0 +1,340 @@
+//! Monster behaviour.
+//!
+//! Every monster with an `Ai` is in one of its states: a sleeping monster wakes up when it
+//! notices the player, hears a fight nearby or gets hurt. Awake, it hunts the player while
+//! in view, and otherwise goes to where it last saw the player before wandering off again.
+//! A badly hurt monster flees for good. A confused monster stumbles around for a while,
+//! and then goes back to whatever it was doing.
+
+use rand::Rng;
+
+use fov::FovMap;
+use colors;
+use game::{attack, message, move_by, Event, GameRng, Messages};
+use map::{is_blocked, Map};
+use object::{Ai, Object, Tactics, PLAYER};
+use path::{self, DijkstraMap, DIRECTIONS, STEP_COST};
+
+// Monsters with a third of their hit points or less run away
+const FLEE_HP_DIVISOR: i32 = 3;
+// The chance a sleeping monster notices the player in view, every turn
+const WAKE_CHANCE: f32 = 0.5;
+// How far away a fight wakes up monsters
+const NOISE_RADIUS: i32 = 8;
+// The distance in steps cowards try to keep from the player
+const COWARD_DISTANCE: i32 = 4;
+
+/// Dijkstra maps around the player, shared by all monsters
+#[derive(Default)]
+pub struct AiMaps {
+    /// Where the player was when the maps were computed
+    origin: Option<(i32, i32)>,
+    approach: DijkstraMap,
+    flee: DijkstraMap,
+}
+
+impl AiMaps {
+    /// Recompute the maps, if the player moved since they were last computed
+    pub fn update(&mut self, map: &Map, player: (i32, i32)) {
+        if self.origin == Some(player) {
+            return;
+        }
+        let blocked = |x: i32, y: i32| map[x as usize][y as usize].blocked;
+        self.approach = DijkstraMap::new(&[player], blocked);
+        self.flee = self.approach.fleeing(blocked);
+        self.origin = Some(player);
+    }
+
+    /// Forget the maps, for when the level changes under them
+    pub fn reset(&mut self) {
+        *self = AiMaps::default();
+    }
+}
+
+/// How a monster reacts to getting hurt from the given position: badly hurt it runs away,
+/// otherwise it wakes up and goes after whoever hurt it
+pub fn hurt(ai: Ai, hp: i32, max_hp: i32, (x, y): (i32, i32)) -> Ai {
+    if hp * FLEE_HP_DIVISOR <= max_hp {
+        return Ai::Fleeing;
+    }
+    match ai {
+        Ai::Asleep | Ai::Wandering => Ai::Hunting { last_seen: (x, y) },
+        ai => ai,
+    }
+}
+
+/// A fight at the given position alerts the monsters that are not busy yet within earshot
+pub fn make_noise(x: i32, y: i32, objects: &mut [Object]) {
+    for object in objects.iter_mut() {
+        let (dx, dy) = (object.x - x, object.y - y);
+        if dx * dx + dy * dy > NOISE_RADIUS * NOISE_RADIUS {
+            continue;
+        }
+        if let Some(Ai::Asleep) | Some(Ai::Wandering) = object.ai {
+            object.ai = Some(Ai::Hunting { last_seen: (x, y) });
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn ai_take_turn(monster_id: usize, map: &Map, objects: &mut [Object], fov_map: &FovMap,
+                    ai_maps: &AiMaps, rng: &mut GameRng, messages: &mut Messages,
+                    events: &mut Vec<Event>) {
+    // If you can see it, it can see you
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let sees_player = fov_map.is_in_fov(monster_x, monster_y);
+    let player_pos = objects[PLAYER].pos();
+
+    let ai = match objects[monster_id].ai.take() {
+        Some(ai) => ai,
+        None => return,
+    };
+    // first change what the monster is doing, based on what it sees
+    let ai = match ai {
+        Ai::Confused { previous, num_turns: 0 } => {
+            message(messages, format!("The {} is no longer confused!", objects[monster_id].name),
+                    colors::RED);
+            *previous
+        }
+        Ai::Confused { previous, num_turns } => Ai::Confused { previous, num_turns: num_turns - 1 },
+        Ai::Asleep if sees_player && rng.gen::<f32>() < WAKE_CHANCE => Ai::Hunting { last_seen: player_pos },
+        Ai::Wandering | Ai::Hunting { .. } if sees_player => Ai::Hunting { last_seen: player_pos },
+        // lost track of the player
+        Ai::Hunting { last_seen } if last_seen == (monster_x, monster_y) => Ai::Wandering,
+        ai => ai,
+    };
+    objects[monster_id].ai = Some(ai.clone());
+
+    let moved = match ai {
+        Ai::Asleep => return,
+        Ai::Wandering => wander(monster_id, map, objects, rng, events),
+        Ai::Fleeing => step_downhill(monster_id, &ai_maps.flee, map, objects, events),
+        Ai::Hunting { last_seen: (x, y) } if !sees_player => {
+            move_astar(monster_id, x, y, map, objects, events);
+            true
+        }
+        Ai::Hunting { .. } => hunt(monster_id, map, objects, ai_maps, events),
+        Ai::Confused { .. } => {
+            // too confused to attack on purpose
+            wander(monster_id, map, objects, rng, events);
+            true
+        }
+    };
+
+    if !moved && objects[monster_id].distance_to(&objects[PLAYER]) < 2.0 &&
+        objects[PLAYER].fighter.is_some_and(|f| f.hp > 0) {
+        // Close enough to attack if the player is still alive
+        attack(monster_id, PLAYER, objects, messages, events);
+    }
+}
+
+/// Close in on the player in view according to the tactics of the monster.
+/// Returns whether the monster moved, if not it may attack.
+fn hunt(monster_id: usize, map: &Map, objects: &mut [Object], ai_maps: &AiMaps,
+        events: &mut Vec<Event>) -> bool {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let steps = ai_maps.approach.value(monster_x, monster_y).map(|value| value / STEP_COST);
+    match objects[monster_id].tactics {
+        Tactics::Coward if steps.is_some_and(|steps| steps < COWARD_DISTANCE) =>
+            step_downhill(monster_id, &ai_maps.flee, map, objects, events),
+        Tactics::Coward if steps.is_some_and(|steps| steps == COWARD_DISTANCE) => false,
+        _ if objects[monster_id].distance_to(&objects[PLAYER]) < 2.0 => false,
+        // move towards player if far away
+        Tactics::Pack => surround(monster_id, &ai_maps.approach, map, objects, events),
+        Tactics::Direct | Tactics::Coward => {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_astar(monster_id, player_x, player_y, map, objects, events);
+            true
+        }
+    }
+}
+
+/// Step in a random free direction, returns whether the monster moved
+fn wander(id: usize, map: &Map, objects: &mut [Object], rng: &mut GameRng,
+          events: &mut Vec<Event>) -> bool {
+    let (x, y) = objects[id].pos();
+    let free: Vec<(i32, i32)> = DIRECTIONS.iter()
+        .cloned()
+        .filter(|&(dx, dy)| !is_blocked(x + dx, y + dy, map, objects))
+        .collect();
+    if free.is_empty() {
+        return false;
+    }
+    let (dx, dy) = free[rng.gen_range(0, free.len())];
+    move_by(id, dx, dy, map, objects, events);
+    true
+}
+
+/// The free tiles around an object, with their value on the Dijkstra map
+fn free_neighbours(id: usize, dijkstra_map: &DijkstraMap, map: &Map, objects: &[Object])
+                   -> Vec<((i32, i32), i32)> {
+    let (x, y) = objects[id].pos();
+    DIRECTIONS.iter()
+        .map(|&(dx, dy)| (x + dx, y + dy))
+        .filter(|&(nx, ny)| !is_blocked(nx, ny, map, objects))
+        .filter_map(|(nx, ny)| dijkstra_map.value(nx, ny).map(|value| ((nx, ny), value)))
+        .collect()
+}
+
+/// Step to the lowest free neighbouring tile on the Dijkstra map, if it is lower than
+/// the current one. Returns whether the object moved.
+fn step_downhill(id: usize, dijkstra_map: &DijkstraMap, map: &Map, objects: &mut [Object],
+                 events: &mut Vec<Event>) -> bool {
+    let (x, y) = objects[id].pos();
+    let current = dijkstra_map.value(x, y).unwrap_or(i32::MAX);
+    let lowest = free_neighbours(id, dijkstra_map, map, objects).into_iter()
+        .filter(|&(_, value)| value < current)
+        .min_by_key(|&(_, value)| value);
+    match lowest {
+        Some(((next_x, next_y), _)) => {
+            move_by(id, next_x - x, next_y - y, map, objects, events);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Close in on the player like `step_downhill`, but away from the rest of the pack:
+/// when the way is blocked by another monster go around it, instead of queueing behind.
+fn surround(id: usize, approach: &DijkstraMap, map: &Map, objects: &mut [Object],
+            events: &mut Vec<Event>) -> bool {
+    let (x, y) = objects[id].pos();
+    let current = approach.value(x, y).unwrap_or(i32::MAX);
+    let crowding = |(tile_x, tile_y): (i32, i32), objects: &[Object]| {
+        objects.iter().enumerate()
+            .filter(|&(other, object)| other != id && other != PLAYER && object.ai.is_some())
+            .filter(|&(_, object)| (object.x - tile_x).abs() <= 1 && (object.y - tile_y).abs() <= 1)
+            .count()
+    };
+    let best = free_neighbours(id, approach, map, objects).into_iter()
+        .filter(|&(_, value)| value <= current)
+        .min_by_key(|&(tile, value)| (value, crowding(tile, objects)));
+    match best {
+        Some(((next_x, next_y), _)) => {
+            move_by(id, next_x - x, next_y - y, map, objects, events);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Take one step along the shortest path around walls and other objects to the target,
+/// or straight towards it when there is no such path
+fn move_astar(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object],
+              events: &mut Vec<Event>) {
+    let (x, y) = objects[id].pos();
+    let path = path::astar((x, y), (target_x, target_y), |x, y| is_blocked(x, y, map, objects));
+    match path.as_ref().and_then(|path| path.first()) {
+        Some(&(next_x, next_y)) => move_by(id, next_x - x, next_y - y, map, objects, events),
+        None => move_towards(id, target_x, target_y, map, objects, events),
+    }
+}
+
+fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object],
+                events: &mut Vec<Event>) {
+    // vector from this object to the target, and distance
+    let dx = target_x - objects[id].x;
+    let dy = target_y - objects[id].y;
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // Normalize it to length 1 then round and convert to integer
+    // so that the movement is restricted to a grid
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+    move_by(id, dx, dy, map, objects, events);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colors;
+    use game::seeded_rng;
+    use map::{Tile, MAP_HEIGHT, MAP_WIDTH};
+    use object::{DeathCallback, Fighter};
+
+    fn orc(x: i32, y: i32, ai: Ai) -> Object {
+        let mut orc = Object::new(x, y, 'o', "orc", colors::WHITE, true);
+        orc.alive = true;
+        orc.fighter = Some(Fighter { base_max_hp: 9, hp: 9, base_defense: 0, base_power: 3, xp: 35,
+                                     on_death: DeathCallback::Monster });
+        orc.ai = Some(ai);
+        orc
+    }
+
+    /// Let the monster take its turn on an open map, with the player at (10, 10). Nothing is
+    /// in view, so the monster only goes by what it remembers.
+    fn take_turn(monster: Object) -> (Object, Vec<Event>, Messages) {
+        let map = vec![vec![Tile::empty(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        let mut player = Object::player();
+        player.set_pos(10, 10);
+        let mut objects = vec![player, monster];
+        let mut ai_maps = AiMaps::default();
+        ai_maps.update(&map, (10, 10));
+        let (mut messages, mut events) = (Messages::default(), vec![]);
+        ai_take_turn(1, &map, &mut objects, &FovMap::from_map(&map), &ai_maps, &mut seeded_rng(1),
+                     &mut messages, &mut events);
+        (objects.remove(1), events, messages)
+    }
+
+    #[test]
+    fn getting_hurt_wakes_a_monster_up() {
+        let hunting = Ai::Hunting { last_seen: (3, 4) };
+        assert_eq!(hurt(Ai::Asleep, 8, 9, (3, 4)), hunting);
+        assert_eq!(hurt(Ai::Wandering, 8, 9, (3, 4)), hunting);
+        // a hunting monster keeps going where it was going
+        assert_eq!(hurt(Ai::Hunting { last_seen: (5, 5) }, 8, 9, (3, 4)), Ai::Hunting { last_seen: (5, 5) });
+    }
+
+    #[test]
+    fn a_badly_hurt_monster_flees() {
+        assert_eq!(hurt(Ai::Asleep, 4, 9, (3, 4)), Ai::Hunting { last_seen: (3, 4) });
+        assert_eq!(hurt(Ai::Asleep, 3, 9, (3, 4)), Ai::Fleeing);
+        assert_eq!(hurt(Ai::Hunting { last_seen: (5, 5) }, 1, 9, (3, 4)), Ai::Fleeing);
+    }
+
+    #[test]
+    fn a_monster_hurt_out_of_view_goes_after_the_player() {
+        let mut monster = orc(30, 10, Ai::Asleep);
+        monster.take_damage(2, (10, 10), &mut Messages::default());
+        assert_eq!(monster.ai, Some(Ai::Hunting { last_seen: (10, 10) }));
+
+        let (monster, events, _) = take_turn(monster);
+        assert_eq!(events, vec![Event::Moved { id: 1, to: monster.pos() }]);
+        assert_eq!(monster.x, 29);
+        assert_eq!(monster.ai, Some(Ai::Hunting { last_seen: (10, 10) }));
+    }
+
+    #[test]
+    fn noise_wakes_the_monsters_within_earshot() {
+        let mut objects = vec![
+            orc(10 + NOISE_RADIUS, 10, Ai::Asleep),
+            orc(10 + NOISE_RADIUS + 1, 10, Ai::Asleep),
+            orc(12, 12, Ai::Wandering),
+            orc(11, 11, Ai::Fleeing),
+        ];
+        make_noise(10, 10, &mut objects);
+        assert_eq!(objects[0].ai, Some(Ai::Hunting { last_seen: (10, 10) }));
+        assert_eq!(objects[1].ai, Some(Ai::Asleep));
+        assert_eq!(objects[2].ai, Some(Ai::Hunting { last_seen: (10, 10) }));
+        assert_eq!(objects[3].ai, Some(Ai::Fleeing));
+    }
+
+    #[test]
+    fn a_hunter_that_loses_track_starts_wandering() {
+        let (monster, events, _) = take_turn(orc(30, 20, Ai::Hunting { last_seen: (30, 20) }));
+        assert_eq!(monster.ai, Some(Ai::Wandering));
+        // and wanders off right away
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn confusion_wears_off() {
+        let hunting = Box::new(Ai::Hunting { last_seen: (20, 20) });
+        let (monster, _, _) = take_turn(orc(30, 20, Ai::Confused { previous: hunting.clone(), num_turns: 2 }));
+        assert_eq!(monster.ai, Some(Ai::Confused { previous: hunting.clone(), num_turns: 1 }));
+
+        let (monster, _, messages) = take_turn(orc(30, 20, Ai::Confused { previous: hunting, num_turns: 0 }));
+        assert_eq!(monster.ai, Some(Ai::Hunting { last_seen: (20, 20) }));
+        assert_eq!(messages.last().map(|message| message.text.as_str()), Some("The orc is no longer confused!"));
+    }
+}
@@ -0,0 +1,31 @@
+//! Colors used by the game core.
+//!
+//! These mirror the libtcod palette so that the renderer can convert them one-to-one,
+//! without the core having to depend on tcod.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+pub const WHITE: Color = Color { r: 255, g: 255, b: 255 };
+pub const RED: Color = Color { r: 255, g: 0, b: 0 };
+pub const DARK_RED: Color = Color { r: 191, g: 0, b: 0 };
+pub const DARK_ORANGE: Color = Color { r: 191, g: 95, b: 0 };
+pub const GOLD: Color = Color { r: 229, g: 191, b: 0 };
+pub const SKY: Color = Color { r: 0, g: 191, b: 255 };
+pub const LIGHT_GREY: Color = Color { r: 159, g: 159, b: 159 };
+pub const ORANGE: Color = Color { r: 255, g: 127, b: 0 };
+pub const LIGHT_YELLOW: Color = Color { r: 255, g: 255, b: 63 };
+pub const LIGHT_GREEN: Color = Color { r: 63, g: 255, b: 63 };
+pub const LIGHT_CYAN: Color = Color { r: 63, g: 255, b: 255 };
+pub const LIGHT_BLUE: Color = Color { r: 63, g: 63, b: 255 };
+pub const GREEN: Color = Color { r: 0, g: 255, b: 0 };
+pub const DARKER_GREEN: Color = Color { r: 0, g: 127, b: 0 };
+pub const YELLOW: Color = Color { r: 255, g: 255, b: 0 };
+pub const DARK_YELLOW: Color = Color { r: 191, g: 191, b: 0 };
+pub const VIOLET: Color = Color { r: 127, g: 0, b: 255 };
+pub const LIGHT_VIOLET: Color = Color { r: 159, g: 63, b: 255 };
@@ -0,0 +1,390 @@
+//! Game content that is read from data files instead of written in code: the monsters and
+//! the items that fill the levels.
+//!
+//! The monster and item files are JSON lists of entries, the spawn counts file is a single
+//! JSON object. The files in `data/` are built into the game, and a data directory can replace
+//! any of them at startup, so new content does not need a rebuild. Every entry is read and
+//! checked on its own, so an error names the entry it is in.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use game::GameRng;
+use items::ItemTemplate;
+use monsters::MonsterTemplate;
+use spawn::{check_total_weight, Distribution, Spawn, SpawnCounts};
+
+pub const MONSTERS_FILE: &str = "monsters.json";
+pub const ITEMS_FILE: &str = "items.json";
+pub const SPAWNS_FILE: &str = "spawns.json";
+
+const BUILTIN_MONSTERS: &str = include_str!("../data/monsters.json");
+const BUILTIN_ITEMS: &str = include_str!("../data/items.json");
+const BUILTIN_SPAWNS: &str = include_str!("../data/spawns.json");
+
+#[derive(Debug)]
+pub enum DataError {
+    Io { file: String, err: io::Error },
+    /// The file is not JSON of the right shape
+    Format { file: String, err: serde_json::Error },
+    /// An entry that is missing fields, has fields of the wrong type or invalid values
+    Entry { file: String, entry: String, reason: String },
+    /// A file without entries that has invalid values
+    Invalid { file: String, reason: String },
+}
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataError::Io { ref file, ref err } => write!(f, "Could not read {}: {}", file, err),
+            DataError::Format { ref file, ref err } => write!(f, "{} is not valid: {}", file, err),
+            DataError::Entry { ref file, ref entry, ref reason } => write!(f, "{}, {}: {}", file, entry, reason),
+            DataError::Invalid { ref file, ref reason } => write!(f, "{}: {}", file, reason),
+        }
+    }
+}
+
+impl Error for DataError {}
+
+/// All the content the levels are filled with
+#[derive(Clone, Debug)]
+pub struct GameData {
+    pub monsters: Vec<MonsterTemplate>,
+    pub items: Vec<ItemTemplate>,
+    /// How many monsters and items each room gets
+    pub spawns: SpawnCounts,
+}
+
+impl GameData {
+    /// Load the data files in the directory, the built in ones stand in for those missing
+    pub fn load(dir: &Path) -> Result<Self, DataError> {
+        let (monsters_file, monsters) = read_or_builtin(dir, MONSTERS_FILE, BUILTIN_MONSTERS)?;
+        let (items_file, items) = read_or_builtin(dir, ITEMS_FILE, BUILTIN_ITEMS)?;
+        let (spawns_file, spawns) = read_or_builtin(dir, SPAWNS_FILE, BUILTIN_SPAWNS)?;
+        let monsters = read_entries(&monsters_file, &monsters, |monster| &monster.name, MonsterTemplate::validate)?;
+        check_weights(&monsters_file, &monsters, |monster| &monster.spawn)?;
+        let items = read_entries(&items_file, &items, |item| &item.name, ItemTemplate::validate)?;
+        check_weights(&items_file, &items, |item| &item.spawn)?;
+        Ok(GameData {
+            monsters,
+            items,
+            spawns: read_object(&spawns_file, &spawns, SpawnCounts::validate)?,
+        })
+    }
+
+    /// Pick a kind of monster that can turn up at the depth, by weight.
+    /// None when no monster turns up that deep.
+    pub fn choose_monster(&self, depth: u32, rng: &mut GameRng) -> Option<&MonsterTemplate> {
+        choose(&self.monsters, |monster| &monster.spawn, depth, rng)
+    }
+
+    /// Pick a kind of item that can turn up at the depth, by weight.
+    /// None when no item turns up that deep.
+    pub fn choose_item(&self, depth: u32, rng: &mut GameRng) -> Option<&ItemTemplate> {
+        choose(&self.items, |item| &item.spawn, depth, rng)
+    }
+
+    /// What a room at the depth can expect to get, from the spawn tables
+    pub fn distribution(&self, depth: u32) -> Distribution {
+        Distribution {
+            depth,
+            max_monsters: self.spawns.max_monsters.at(depth),
+            max_items: self.spawns.max_items.at(depth),
+            monsters: Distribution::chances(self.monsters.iter()
+                .map(|monster| (&monster.name, monster.spawn.weight_at(depth)))),
+            items: Distribution::chances(self.items.iter()
+                .map(|item| (&item.name, item.spawn.weight_at(depth)))),
+        }
+    }
+}
+
+impl Default for GameData {
+    /// The data built into the game
+    fn default() -> Self {
+        let builtin = || -> Result<GameData, DataError> {
+            let monsters = read_entries(MONSTERS_FILE, BUILTIN_MONSTERS, |monster| &monster.name,
+                                        MonsterTemplate::validate)?;
+            check_weights(MONSTERS_FILE, &monsters, |monster| &monster.spawn)?;
+            let items = read_entries(ITEMS_FILE, BUILTIN_ITEMS, |item| &item.name, ItemTemplate::validate)?;
+            check_weights(ITEMS_FILE, &items, |item| &item.spawn)?;
+            Ok(GameData {
+                monsters,
+                items,
+                spawns: read_object(SPAWNS_FILE, BUILTIN_SPAWNS, SpawnCounts::validate)?,
+            })
+        };
+        builtin().expect("the built in data is valid")
+    }
+}
+
+/// The path and contents of the data file in the directory, or the built in contents without one
+fn read_or_builtin(dir: &Path, file: &str, builtin: &str) -> Result<(String, String), DataError> {
+    let path = dir.join(file);
+    if !path.exists() {
+        return Ok((file.into(), builtin.into()));
+    }
+    let file = path.display().to_string();
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok((file, text)),
+        Err(err) => Err(DataError::Io { file, err }),
+    }
+}
+
+/// Read and check the entries of the list in the file
+fn read_entries<T, N, V>(file: &str, text: &str, name: N, validate: V) -> Result<Vec<T>, DataError>
+    where T: DeserializeOwned, N: Fn(&T) -> &String, V: Fn(&T) -> Result<(), String>
+{
+    let entries = parse_entries(file, text)?;
+    check_entries(file, &entries, name, validate)?;
+    Ok(entries)
+}
+
+/// Read and check the single object in the file
+fn read_object<T, V>(file: &str, text: &str, validate: V) -> Result<T, DataError>
+    where T: DeserializeOwned, V: Fn(&T) -> Result<(), String>
+{
+    let object = serde_json::from_str(text).map_err(|err| DataError::Format { file: file.into(), err })?;
+    validate(&object).map_err(|reason| DataError::Invalid { file: file.into(), reason })?;
+    Ok(object)
+}
+
+/// Check that the weights of the entries in the file add up to a total they can be chosen by
+fn check_weights<T, S>(file: &str, templates: &[T], spawn: S) -> Result<(), DataError>
+    where S: Fn(&T) -> &Spawn
+{
+    let spawns: Vec<&Spawn> = templates.iter().map(spawn).collect();
+    check_total_weight(&spawns).map_err(|reason| DataError::Invalid { file: file.into(), reason })
+}
+
+/// Read every entry of the list in the file on its own, so that errors can name the entry
+fn parse_entries<T: DeserializeOwned>(file: &str, text: &str) -> Result<Vec<T>, DataError> {
+    let entries: Vec<Value> = serde_json::from_str(text)
+        .map_err(|err| DataError::Format { file: file.into(), err })?;
+    entries.into_iter().enumerate()
+        .map(|(index, entry)| {
+            let name = entry_name(index, &entry);
+            serde_json::from_value(entry)
+                .map_err(|err| DataError::Entry { file: file.into(), entry: name, reason: err.to_string() })
+        })
+        .collect()
+}
+
+/// Check every entry with the validation function, and that no two entries share a name
+fn check_entries<T, N, V>(file: &str, entries: &[T], name: N, validate: V) -> Result<(), DataError>
+    where N: Fn(&T) -> &String, V: Fn(&T) -> Result<(), String>
+{
+    for (index, entry) in entries.iter().enumerate() {
+        let invalid = |reason| DataError::Entry {
+            file: file.into(),
+            entry: format!("entry {} \"{}\"", index + 1, name(entry)),
+            reason,
+        };
+        validate(entry).map_err(invalid)?;
+        if entries[..index].iter().any(|other| name(other) == name(entry)) {
+            return Err(invalid("there is an earlier entry with the same name".into()));
+        }
+    }
+    Ok(())
+}
+
+/// How errors refer to an entry: its position, and its name if it has one
+fn entry_name(index: usize, entry: &Value) -> String {
+    match entry.get("name").and_then(Value::as_str) {
+        Some(name) => format!("entry {} \"{}\"", index + 1, name),
+        None => format!("entry {}", index + 1),
+    }
+}
+
+/// Pick one of the templates that can turn up at the depth, with a chance in proportion to
+/// its weight there. None when none of them turns up that deep.
+fn choose<'a, T, S>(templates: &'a [T], spawn: S, depth: u32, rng: &mut GameRng) -> Option<&'a T>
+    where S: Fn(&T) -> &Spawn
+{
+    let mut choices: Vec<Weighted<usize>> = templates.iter().enumerate()
+        .map(|(index, template)| Weighted { weight: spawn(template).weight_at(depth), item: index })
+        .filter(|choice| choice.weight > 0)
+        .collect();
+    if choices.is_empty() {
+        return None;
+    }
+    Some(&templates[WeightedChoice::new(&mut choices).ind_sample(rng)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use game::seeded_rng;
+
+    fn goblin() -> Value {
+        serde_json::json!({
+            "name": "goblin",
+            "glyph": "g",
+            "color": { "r": 0, "g": 200, "b": 0 },
+            "hp": 6,
+            "defense": 0,
+            "power": 2,
+            "xp": 20,
+            "ai": "coward",
+            "spawn": { "weight": 10 }
+        })
+    }
+
+    /// Read a monster file holding an orc, followed by the given entry
+    fn monsters(entry: Value) -> Result<Vec<MonsterTemplate>, DataError> {
+        let mut orc = goblin();
+        orc["name"] = "orc".into();
+        let text = Value::Array(vec![orc, entry]).to_string();
+        read_entries(MONSTERS_FILE, &text, |monster: &MonsterTemplate| &monster.name, MonsterTemplate::validate)
+    }
+
+    fn entry_error(result: Result<Vec<MonsterTemplate>, DataError>) -> (String, String) {
+        match result {
+            Err(DataError::Entry { entry, reason, .. }) => (entry, reason),
+            other => panic!("expected an invalid entry, got {:?}", other.map(|monsters| monsters.len())),
+        }
+    }
+
+    #[test]
+    fn the_built_in_data_is_valid() {
+        let data = GameData::default();
+        assert!(data.monsters.iter().any(|monster| monster.name == "orc"));
+        assert!(!data.items.is_empty());
+    }
+
+    #[test]
+    fn monsters_are_read_from_their_entries() {
+        let monsters = monsters(goblin()).unwrap();
+        assert_eq!(monsters.len(), 2);
+        assert_eq!(monsters[1].name, "goblin");
+        assert_eq!(monsters[1].spawn.min_depth, 1);
+        assert_eq!(monsters[1].spawn.max_depth, None);
+    }
+
+    #[test]
+    fn a_missing_field_names_the_entry() {
+        let mut entry = goblin();
+        entry.as_object_mut().unwrap().remove("power");
+        let (entry, reason) = entry_error(monsters(entry));
+        assert_eq!(entry, "entry 2 \"goblin\"");
+        assert!(reason.contains("power"), "{}", reason);
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let mut entry = goblin();
+        entry["speed"] = 3.into();
+        let (_, reason) = entry_error(monsters(entry));
+        assert!(reason.contains("speed"), "{}", reason);
+
+        let mut entry = goblin();
+        entry["spawn"]["min_dpeth"] = 2.into();
+        let (_, reason) = entry_error(monsters(entry));
+        assert!(reason.contains("min_dpeth"), "{}", reason);
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        let mut entry = goblin();
+        entry["hp"] = 0.into();
+        assert_eq!(entry_error(monsters(entry)).1, "hp must be positive, not 0");
+
+        let mut entry = goblin();
+        entry["spawn"]["min_depth"] = 4.into();
+        entry["spawn"]["max_depth"] = 2.into();
+        assert_eq!(entry_error(monsters(entry)).1, "max_depth 2 is less than min_depth 4");
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        let mut entry = goblin();
+        entry["name"] = "orc".into();
+        let (entry, reason) = entry_error(monsters(entry));
+        assert_eq!(entry, "entry 2 \"orc\"");
+        assert_eq!(reason, "there is an earlier entry with the same name");
+    }
+
+    #[test]
+    fn errors_in_a_data_directory_name_the_file() {
+        let dir = env::temp_dir().join(format!("roguelike-data-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(MONSTERS_FILE);
+        fs::write(&file, "[{\"name\": \"ghost\"}]").unwrap();
+        let result = GameData::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(DataError::Entry { file: ref name, ref entry, .. }) => {
+                assert_eq!(name, &file.display().to_string());
+                assert_eq!(entry, "entry 1 \"ghost\"");
+            }
+            other => panic!("expected an invalid entry, got {:?}", other.map(|data| data.monsters.len())),
+        }
+    }
+
+    #[test]
+    fn items_with_the_equip_effect_need_an_equip_entry() {
+        let text = serde_json::json!([{
+            "name": "cloak",
+            "glyph": "[",
+            "color": { "r": 100, "g": 100, "b": 100 },
+            "effect": { "kind": "equip" },
+            "spawn": { "weight": 5 }
+        }]).to_string();
+        let result = read_entries(ITEMS_FILE, &text, |item: &ItemTemplate| &item.name, ItemTemplate::validate);
+        match result {
+            Err(DataError::Entry { ref entry, ref reason, .. }) => {
+                assert_eq!(entry, "entry 1 \"cloak\"");
+                assert_eq!(reason, "an item with the equip effect needs an equip entry");
+            }
+            other => panic!("expected an invalid entry, got {:?}", other.map(|items| items.len())),
+        }
+    }
+
+    #[test]
+    fn weights_must_add_up_to_a_total_that_can_be_chosen_by() {
+        let dir = env::temp_dir().join(format!("roguelike-weights-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut heavy = goblin();
+        heavy["name"] = "heavy goblin".into();
+        heavy["spawn"]["weight"] = u32::MAX.into();
+        fs::write(dir.join(MONSTERS_FILE), Value::Array(vec![goblin(), heavy]).to_string()).unwrap();
+        let result = GameData::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(DataError::Invalid { ref reason, .. }) =>
+                assert_eq!(reason, "the weights add up to more than 4294967295 at depth 1"),
+            other => panic!("expected invalid weights, got {:?}", other.map(|data| data.monsters.len())),
+        }
+    }
+
+    #[test]
+    fn only_kinds_that_turn_up_at_the_depth_are_chosen() {
+        let mut shallow = goblin();
+        shallow["spawn"]["max_depth"] = 2.into();
+        let mut deep = goblin();
+        deep["name"] = "deep goblin".into();
+        deep["spawn"]["min_depth"] = 3.into();
+        let text = Value::Array(vec![shallow, deep]).to_string();
+        let data = GameData {
+            monsters: read_entries(MONSTERS_FILE, &text, |monster: &MonsterTemplate| &monster.name,
+                                   MonsterTemplate::validate).unwrap(),
+            ..GameData::default()
+        };
+
+        let mut rng = seeded_rng(1);
+        for _ in 0..20 {
+            assert_eq!(data.choose_monster(2, &mut rng).unwrap().name, "goblin");
+            assert_eq!(data.choose_monster(3, &mut rng).unwrap().name, "deep goblin");
+        }
+        let names: Vec<_> = data.distribution(2).monsters.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["goblin"]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Field of view computation.
+//!
+//! A small stand-in for `tcod::map::Map`, so that the game core can decide what the
+//! player (and thus the monsters) can see without opening a window.
+
+use map::{Map, MAP_HEIGHT, MAP_WIDTH};
+use path;
+
+pub struct FovMap {
+    width: i32,
+    height: i32,
+    transparent: Vec<bool>,
+    walkable: Vec<bool>,
+    in_fov: Vec<bool>,
+}
+
+impl FovMap {
+    pub fn new(width: i32, height: i32) -> Self {
+        let size = (width * height) as usize;
+        FovMap {
+            width,
+            height,
+            transparent: vec![false; size],
+            walkable: vec![false; size],
+            in_fov: vec![false; size],
+        }
+    }
+
+    /// Create a field of view map matching the tiles of the given map
+    pub fn from_map(map: &Map) -> Self {
+        let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                fov_map.set(x, y,
+                            !map[x as usize][y as usize].block_sight,
+                            !map[x as usize][y as usize].blocked);
+            }
+        }
+        fov_map
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, transparent: bool, walkable: bool) {
+        let index = self.index(x, y);
+        self.transparent[index] = transparent;
+        self.walkable[index] = walkable;
+    }
+
+    pub fn is_walkable(&self, x: i32, y: i32) -> bool {
+        self.in_bounds(x, y) && self.walkable[self.index(x, y)]
+    }
+
+    pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        self.in_bounds(x, y) && self.in_fov[self.index(x, y)]
+    }
+
+    /// Basic ray casting: a ray is cast from the origin to every cell on the border
+    /// of the square enclosing the radius, and stops at the first opaque cell.
+    /// A radius of 0 means the view is unlimited.
+    pub fn compute_fov(&mut self, origin_x: i32, origin_y: i32, radius: i32, light_walls: bool) {
+        for visible in &mut self.in_fov {
+            *visible = false;
+        }
+        if !self.in_bounds(origin_x, origin_y) {
+            return;
+        }
+        let index = self.index(origin_x, origin_y);
+        self.in_fov[index] = true;
+
+        let radius = if radius > 0 { radius } else { self.width.max(self.height) };
+        let (x_min, x_max) = (origin_x - radius, origin_x + radius);
+        let (y_min, y_max) = (origin_y - radius, origin_y + radius);
+        for x in x_min..(x_max + 1) {
+            self.cast_ray(origin_x, origin_y, x, y_min, radius, light_walls);
+            self.cast_ray(origin_x, origin_y, x, y_max, radius, light_walls);
+        }
+        for y in (y_min + 1)..y_max {
+            self.cast_ray(origin_x, origin_y, x_min, y, radius, light_walls);
+            self.cast_ray(origin_x, origin_y, x_max, y, radius, light_walls);
+        }
+    }
+
+    fn cast_ray(&mut self, origin_x: i32, origin_y: i32, target_x: i32, target_y: i32,
+                radius: i32, light_walls: bool) {
+        for (x, y) in path::line((origin_x, origin_y), (target_x, target_y)) {
+            if !self.in_bounds(x, y) {
+                return;
+            }
+            let (ox, oy) = (x - origin_x, y - origin_y);
+            if ox * ox + oy * oy > radius * radius {
+                return;
+            }
+            let index = self.index(x, y);
+            if self.transparent[index] {
+                self.in_fov[index] = true;
+            } else {
+                if light_walls {
+                    self.in_fov[index] = true;
+                }
+                return;
+            }
+        }
+    }
+
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    fn index(&self, x: i32, y: i32) -> usize {
+        (y * self.width + x) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An open 20 by 20 map with a wall from (10, 5) down to (10, 14)
+    fn walled() -> FovMap {
+        let mut fov_map = FovMap::new(20, 20);
+        for y in 0..20 {
+            for x in 0..20 {
+                let wall = x == 10 && (5..15).contains(&y);
+                fov_map.set(x, y, !wall, !wall);
+            }
+        }
+        fov_map
+    }
+
+    #[test]
+    fn walls_block_the_view() {
+        let mut fov_map = walled();
+        fov_map.compute_fov(5, 10, 0, true);
+        assert!(fov_map.is_in_fov(5, 10));
+        assert!(fov_map.is_in_fov(9, 10));
+        assert!(fov_map.is_in_fov(10, 10));
+        assert!(!fov_map.is_in_fov(11, 10));
+        assert!(!fov_map.is_in_fov(15, 10));
+        // around the end of the wall
+        assert!(fov_map.is_in_fov(12, 18));
+    }
+
+    #[test]
+    fn walls_are_only_lit_when_asked() {
+        let mut fov_map = walled();
+        fov_map.compute_fov(5, 10, 0, false);
+        assert!(fov_map.is_in_fov(9, 10));
+        assert!(!fov_map.is_in_fov(10, 10));
+    }
+
+    #[test]
+    fn the_radius_limits_the_view() {
+        let mut fov_map = walled();
+        fov_map.compute_fov(5, 10, 3, true);
+        assert!(fov_map.is_in_fov(8, 10));
+        assert!(!fov_map.is_in_fov(9, 10));
+        assert!(fov_map.is_in_fov(7, 12));
+        assert!(!fov_map.is_in_fov(8, 13));
+    }
+
+    #[test]
+    fn nothing_is_seen_from_outside_the_map() {
+        let mut fov_map = walled();
+        fov_map.compute_fov(5, 10, 0, true);
+        fov_map.compute_fov(-1, 10, 0, true);
+        assert!(!fov_map.is_in_fov(5, 10));
+        assert!(!fov_map.is_in_fov(-1, 10));
+    }
+}
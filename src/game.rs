@@ -0,0 +1,1203 @@
+//! The game state and the rules for advancing it one player action at a time.
+//!
+//! Nothing in here knows about rendering: a frontend feeds `Action`s into `Game::step`
+//! and draws whatever state results.
+
+use rand::{self, Isaac64Rng, Rng, SeedableRng};
+use std::cmp;
+use std::collections::{vec_deque, VecDeque};
+
+use colors::{self, Color};
+use data::GameData;
+use fov::FovMap;
+use map::{self, is_blocked, Map, MapGenerator};
+use ai::{self, AiMaps};
+use object::{Ai, Object, PLAYER};
+use path;
+
+// Message log constants
+pub const MSG_HEIGHT: usize = 6;
+/// How many messages the log keeps by default
+pub const MESSAGE_HISTORY: usize = 1000;
+
+/// A line in the message log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub text: String,
+    pub color: Color,
+    /// The turn the message was last logged in
+    pub turn: u32,
+    /// How many times in a row the message was logged
+    pub count: u32,
+}
+
+impl Message {
+    /// The text with the number of repeats, "orc attacks you x3"
+    pub fn display_text(&self) -> String {
+        if self.count > 1 {
+            format!("{} x{}", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+/// The message log, keeping the newest messages up to its capacity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Messages {
+    entries: VecDeque<Message>,
+    capacity: usize,
+    /// Stamped on the messages that are logged
+    turn: u32,
+}
+
+impl Messages {
+    pub fn new(capacity: usize) -> Self {
+        Messages { entries: VecDeque::new(), capacity: cmp::max(1, capacity), turn: 0 }
+    }
+
+    /// Change how many messages are kept, dropping the oldest ones that no longer fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = cmp::max(1, capacity);
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Log a message, a repeat of the last one is counted on it instead
+    pub fn add<T: Into<String>>(&mut self, text: T, color: Color) {
+        let text = text.into();
+        if let Some(last) = self.entries.back_mut() {
+            if last.text == text && last.color == color {
+                last.count += 1;
+                last.turn = self.turn;
+                return;
+            }
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Message { text, color, turn: self.turn, count: 1 });
+    }
+
+    /// The messages from oldest to newest
+    pub fn iter(&self) -> vec_deque::Iter<'_, Message> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last(&self) -> Option<&Message> {
+        self.entries.back()
+    }
+
+    fn set_turn(&mut self, turn: u32) {
+        self.turn = turn;
+    }
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages::new(MESSAGE_HISTORY)
+    }
+}
+
+// Fov properties
+pub const FOV_LIGHT_WALLS: bool = true;
+pub const TORCH_RADIUS: i32 = 10;
+
+// Experience needed for the next level: the base plus the factor times the current level
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
+// What each choice at a level up adds
+pub const LEVEL_UP_HP: i32 = 10;
+pub const LEVEL_UP_POWER: i32 = 1;
+pub const LEVEL_UP_DEFENSE: i32 = 1;
+
+// How far the player can throw an item
+pub const THROW_RANGE: f32 = 8.0;
+
+/// The random number generator owned by the game. Every random decision is drawn from it,
+/// so that a seed plus the actions taken always replay the same game.
+pub type GameRng = Isaac64Rng;
+
+/// Create the game random number generator from a seed
+pub fn seeded_rng(seed: u64) -> GameRng {
+    Isaac64Rng::from_seed(&[seed][..])
+}
+
+fn unseeded_rng() -> GameRng {
+    seeded_rng(0)
+}
+
+/// The generator a step starts with, so the rolls of a step only depend on the seed and
+/// where the game is at, not on how it got there
+fn step_rng(seed: u64, turn: u32, depth: u32) -> GameRng {
+    Isaac64Rng::from_seed(&[seed, u64::from(turn), u64::from(depth)][..])
+}
+
+fn empty_fov_map() -> FovMap {
+    FovMap::new(0, 0)
+}
+
+/// Pick a fresh seed, for when the player did not supply one
+pub fn random_seed() -> u64 {
+    rand::thread_rng().gen()
+}
+
+/// Something the player wants to do this turn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Move a step in the direction of the offset, attacking whatever is in the way
+    Move(i32, i32),
+    /// Stay in place for a turn
+    Wait,
+    /// Pick up the item the player is standing on
+    PickUp,
+    /// Use the item at the given index of the inventory, on the target tile if it needs one.
+    /// Items that can be worn are put on. Like everything done from the inventory menu this
+    /// does not take a turn; only dropping and throwing do, as the item leaves the player.
+    UseItem(usize, Option<(i32, i32)>),
+    /// Take off the item at the given index of the player's equipment, without taking a turn
+    /// like putting it on
+    Unequip(usize),
+    /// Put the item at the given index of the inventory on the floor
+    Drop(usize),
+    /// Throw the item at the given index of the inventory towards a tile
+    Throw(usize, (i32, i32)),
+    /// Take the stairs the player is standing on down to the next level
+    Descend,
+    /// Spend a level up the player has enough experience for on one of the stats
+    LevelUp(Stat),
+}
+
+/// The stats the player can raise when gaining a level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stat {
+    Hp,
+    Power,
+    Defense,
+}
+
+/// Something that happened while performing a step
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Moved { id: usize, to: (i32, i32) },
+    Attacked { attacker: usize, target: usize, damage: i32 },
+    Died { id: usize },
+    PickedUp { name: String },
+    UsedItem { name: String },
+    Equipped { name: String },
+    Unequipped { name: String },
+    Dropped { name: String },
+    Thrown { name: String, to: (i32, i32) },
+    Descended { depth: u32 },
+    LeveledUp { level: i32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+}
+
+enum UseResult {
+    UsedUp,
+    Cancelled
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Game {
+    pub map: Map,
+    pub objects: Vec<Object>,
+    pub inventory: Vec<Object>,
+    pub messages: Messages,
+    /// Derived from the map, rebuilt when a game is loaded
+    #[serde(skip, default = "empty_fov_map")]
+    pub fov_map: FovMap,
+    /// Number of turns the player has taken
+    pub turn: u32,
+    /// How deep the player is in the dungeon, starting at 1
+    pub depth: u32,
+    /// Lays out every new level
+    pub generator: MapGenerator,
+    /// The seed the game was started with
+    pub seed: u64,
+    /// Not saved, reseeded from the seed, turn and depth at the start of every step
+    #[serde(skip, default = "unseeded_rng")]
+    pub rng: GameRng,
+    /// Derived from the map and the player position, recomputed when needed
+    #[serde(skip)]
+    ai_maps: AiMaps,
+    /// What new levels are filled with, not saved but loaded when the game starts
+    #[serde(skip)]
+    pub data: GameData,
+}
+
+impl Game {
+    /// Start a new game, the seed determines the dungeon and every other random roll
+    pub fn new(seed: u64, generator: MapGenerator) -> Self {
+        Game::with_data(seed, generator, GameData::default())
+    }
+
+    /// Start a new game with the given content instead of the built in data
+    pub fn with_data(seed: u64, generator: MapGenerator, data: GameData) -> Self {
+        let mut rng = seeded_rng(seed);
+        let mut objects = vec![Object::player()];
+        let map = map::make_map(&mut objects, &mut rng, generator, &data, 1);
+        let fov_map = FovMap::from_map(&map);
+        let mut game = Game {
+            map,
+            objects,
+            inventory: vec![],
+            messages: Messages::default(),
+            fov_map,
+            turn: 0,
+            depth: 1,
+            generator,
+            seed,
+            rng,
+            ai_maps: AiMaps::default(),
+            data,
+        };
+        game.compute_fov();
+
+        // Print welcome message
+        message(&mut game.messages,
+                "Welcome stranger! Prepare to perish in the ST horror dungeon",
+                colors::RED);
+        game
+    }
+
+    /// Perform the player action, and let the monsters respond if it took a turn
+    pub fn step(&mut self, action: Action) -> Vec<Event> {
+        let mut events = vec![];
+        if !self.objects[PLAYER].alive {
+            return events;
+        }
+        // a loaded game rolls the same numbers as one that was never saved
+        self.rng = step_rng(self.seed, self.turn, self.depth);
+        // whatever happens now belongs to the turn being played
+        self.messages.set_turn(self.turn + 1);
+
+        let player_action = match action {
+            Action::Wait | Action::Move(0, 0) => PlayerAction::TookTurn,
+            Action::Move(dx, dy) => {
+                // the player moves one tile at a time, whatever the offset
+                player_move_or_attack(dx.signum(), dy.signum(), &self.map, &mut self.objects, &mut self.messages, &mut events);
+                PlayerAction::TookTurn
+            }
+            Action::PickUp => {
+                let item_id = self.objects.iter().position(|object| {
+                    object.pos() == self.objects[PLAYER].pos() && object.item.is_some()
+                });
+                if let Some(item_id) = item_id {
+                    pick_item_up(item_id, &mut self.objects, &mut self.inventory, &mut self.messages, &mut events);
+                }
+                PlayerAction::DidntTakeTurn
+            }
+            Action::UseItem(inventory_id, target) => {
+                if inventory_id < self.inventory.len() {
+                    use_item(inventory_id, target, &mut self.inventory, &mut self.objects, &self.fov_map,
+                             &mut self.messages, &mut events);
+                }
+                PlayerAction::DidntTakeTurn
+            }
+            Action::Drop(inventory_id) if inventory_id < self.inventory.len() => {
+                let mut item = take_one(&mut self.inventory, inventory_id);
+                let (x, y) = self.objects[PLAYER].pos();
+                item.set_pos(x, y);
+                message(&mut self.messages, format!("You dropped a {}.", item.name), colors::YELLOW);
+                events.push(Event::Dropped { name: item.name.clone() });
+                self.objects.push(item);
+                PlayerAction::TookTurn
+            }
+            Action::Throw(inventory_id, target) if inventory_id < self.inventory.len() => {
+                if in_target_range(target, Some(THROW_RANGE), &self.objects, &self.fov_map) {
+                    let item = take_one(&mut self.inventory, inventory_id);
+                    self.throw(item, target, &mut events);
+                    PlayerAction::TookTurn
+                } else {
+                    message(&mut self.messages, "You cannot throw that far.", colors::RED);
+                    PlayerAction::DidntTakeTurn
+                }
+            }
+            Action::Drop(_) | Action::Throw(..) => PlayerAction::DidntTakeTurn,
+            Action::Unequip(index) => {
+                if let Some(item) = self.objects[PLAYER].unequip(index) {
+                    message(&mut self.messages, format!("You take off the {}.", item.name), colors::LIGHT_YELLOW);
+                    events.push(Event::Unequipped { name: item.name.clone() });
+                    self.inventory.push(item);
+                }
+                PlayerAction::DidntTakeTurn
+            }
+            Action::LevelUp(stat) => {
+                if self.can_level_up() {
+                    self.level_up(stat, &mut events);
+                }
+                PlayerAction::DidntTakeTurn
+            }
+            Action::Descend => {
+                let player_pos = self.objects[PLAYER].pos();
+                let on_stairs = self.objects.iter().any(|object| {
+                    object.pos() == player_pos && object.stairs
+                });
+                if on_stairs {
+                    self.next_level(&mut events);
+                }
+                PlayerAction::DidntTakeTurn
+            }
+        };
+
+        if player_action == PlayerAction::TookTurn {
+            self.turn += 1;
+            self.compute_fov();
+            self.ai_maps.update(&self.map, self.objects[PLAYER].pos());
+            for id in 0..self.objects.len() {
+                if self.objects[id].ai.is_some() {
+                    ai::ai_take_turn(id, &self.map, &mut self.objects, &self.fov_map, &self.ai_maps,
+                                     &mut self.rng, &mut self.messages, &mut events);
+                }
+            }
+        }
+        events
+    }
+
+    /// The tiles an item thrown at the target passes, ending where it lands: on the first
+    /// monster in the way, in front of the first wall or other blocking object, or at the target
+    pub fn throw_path(&self, target: (i32, i32)) -> Vec<(i32, i32)> {
+        let mut tiles = vec![];
+        for (x, y) in path::line(self.objects[PLAYER].pos(), target) {
+            if self.map[x as usize][y as usize].blocked {
+                break;
+            }
+            let blocker = self.objects.iter().find(|object| object.blocks && object.pos() == (x, y));
+            if let Some(blocker) = blocker {
+                if blocker.fighter.is_some() {
+                    tiles.push((x, y));
+                }
+                break;
+            }
+            tiles.push((x, y));
+        }
+        tiles
+    }
+
+    fn throw(&mut self, mut item: Object, target: (i32, i32), events: &mut Vec<Event>) {
+        let (x, y) = self.throw_path(target).last().cloned().unwrap_or_else(|| self.objects[PLAYER].pos());
+        item.set_pos(x, y);
+        message(&mut self.messages, format!("You throw the {}.", item.name), colors::YELLOW);
+        events.push(Event::Thrown { name: item.name.clone(), to: (x, y) });
+
+        let hit = self.objects.iter().position(|object| {
+            object.pos() == (x, y) && object.fighter.is_some() && object.alive
+        });
+        match (hit, item.throw_damage) {
+            (Some(id), Some(damage)) if id != PLAYER => {
+                message(&mut self.messages,
+                        format!("The {} hits the {} for {} hit points.", item.name, self.objects[id].name, damage),
+                        colors::ORANGE);
+                damage_by_player(id, damage, &mut self.objects, &mut self.messages, events);
+            }
+            (Some(id), _) if id != PLAYER => {
+                message(&mut self.messages,
+                        format!("The {} bounces off the {}.", item.name, self.objects[id].name),
+                        colors::WHITE);
+            }
+            _ => {}
+        }
+        self.objects.push(item);
+    }
+
+    /// Describe what the player knows about a tile: a line for the tile itself, followed by
+    /// one for each object on it in view
+    pub fn describe(&self, x: i32, y: i32) -> Vec<String> {
+        if x < 0 || y < 0 || x >= map::MAP_WIDTH || y >= map::MAP_HEIGHT {
+            return vec![];
+        }
+        let tile = &self.map[x as usize][y as usize];
+        let visible = self.fov_map.is_in_fov(x, y);
+        if !visible && !tile.explored {
+            return vec!["You have not explored this place yet.".into()];
+        }
+
+        let kind = if tile.blocked { "A wall" } else { "The floor" };
+        let mut lines = vec![if visible {
+            format!("{}.", kind)
+        } else {
+            format!("{}, as you remember it.", kind)
+        }];
+        lines.extend(self.objects.iter()
+            .filter(|object| object.pos() == (x, y) && (visible || object.always_visible))
+            .map(|object| object.description()));
+        lines
+    }
+
+    /// The experience the player needs for the next level
+    pub fn level_up_xp(&self) -> i32 {
+        LEVEL_UP_BASE + self.objects[PLAYER].level * LEVEL_UP_FACTOR
+    }
+
+    /// Whether the player has gained enough experience for a level up,
+    /// the frontend should ask which stat to raise with `Action::LevelUp`
+    pub fn can_level_up(&self) -> bool {
+        self.objects[PLAYER].fighter.is_some_and(|f| f.xp >= self.level_up_xp())
+    }
+
+    fn level_up(&mut self, stat: Stat, events: &mut Vec<Event>) {
+        let level_up_xp = self.level_up_xp();
+        let player = &mut self.objects[PLAYER];
+        player.level += 1;
+        if let Some(ref mut fighter) = player.fighter {
+            fighter.xp -= level_up_xp;
+            match stat {
+                Stat::Hp => {
+                    fighter.base_max_hp += LEVEL_UP_HP;
+                    fighter.hp += LEVEL_UP_HP;
+                }
+                Stat::Power => fighter.base_power += LEVEL_UP_POWER,
+                Stat::Defense => fighter.base_defense += LEVEL_UP_DEFENSE,
+            }
+        }
+        message(&mut self.messages,
+                format!("Your battle skills grow stronger! You reached level {}!", player.level),
+                colors::YELLOW);
+        events.push(Event::LeveledUp { level: player.level });
+    }
+
+    /// Go down to a freshly generated level, carrying over the player and inventory
+    fn next_level(&mut self, events: &mut Vec<Event>) {
+        message(&mut self.messages, "You descend deeper into the heart of the dungeon...", colors::RED);
+        self.depth += 1;
+        self.map = map::make_map(&mut self.objects, &mut self.rng, self.generator, &self.data, self.depth);
+        self.fov_map = FovMap::from_map(&self.map);
+        self.ai_maps.reset();
+        self.compute_fov();
+        events.push(Event::Descended { depth: self.depth });
+    }
+
+    /// Recompute what the player can see, and mark it as explored
+    pub fn compute_fov(&mut self) {
+        let (player_x, player_y) = self.objects[PLAYER].pos();
+        self.fov_map.compute_fov(player_x, player_y, TORCH_RADIUS, FOV_LIGHT_WALLS);
+
+        for (x, column) in self.map.iter_mut().enumerate() {
+            for (y, tile) in column.iter_mut().enumerate() {
+                if self.fov_map.is_in_fov(x as i32, y as i32) {
+                    tile.explored = true;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Game::new(random_seed(), MapGenerator::Rooms)
+    }
+}
+
+pub fn message<T: Into<String>>(messages: &mut Messages, message: T, color: Color) {
+    messages.add(message, color);
+}
+
+fn pick_item_up(object_id: usize,
+                objects: &mut Vec<Object>,
+                inventory: &mut Vec<Object>,
+                messages: &mut Messages,
+                events: &mut Vec<Event>) {
+    // an item that stacks with a carried one needs no room, worn items do as they go back
+    // into the inventory when they are taken off
+    let stack = inventory.iter().position(|carried| carried.stacks_with(&objects[object_id]));
+    let worn = objects[PLAYER].equipment.as_ref().map_or(0, |equipment| equipment.items.len());
+    if stack.is_none() && inventory.len() + worn >= 26 {
+        message(messages, format!("Your inventory is full, cannot pick up {}",
+                                  objects[object_id].name), colors::RED);
+    } else {
+        let item = objects.swap_remove(object_id);
+        message(messages, format!("You picked up {}", a_or_count(&item)), colors::GREEN);
+        events.push(Event::PickedUp { name: item.name.clone() });
+        match stack {
+            Some(stack) => inventory[stack].count += item.count,
+            None => inventory.push(item),
+        }
+    }
+}
+
+/// "a healing potion", or "3 healing potions" for a stack
+fn a_or_count(item: &Object) -> String {
+    if item.count == 1 {
+        format!("a {}", item.name)
+    } else {
+        item.display_name()
+    }
+}
+
+/// Take a single item out of the inventory, splitting it off its stack
+fn take_one(inventory: &mut Vec<Object>, inventory_id: usize) -> Object {
+    if inventory[inventory_id].count > 1 {
+        inventory[inventory_id].count -= 1;
+        let mut item = inventory[inventory_id].clone();
+        item.count = 1;
+        item
+    } else {
+        inventory.remove(inventory_id)
+    }
+}
+
+fn use_item(inventory_id: usize, target: Option<(i32, i32)>, inventory: &mut Vec<Object>,
+            objects: &mut [Object], fov_map: &FovMap, messages: &mut Messages, events: &mut Vec<Event>) {
+    use object::Item::*;
+    // Cast the effect of the item, with the numbers it came with
+    if let Some(item) = inventory[inventory_id].item {
+        let result = match item {
+            Heal { amount } => cast_heal(amount, objects, messages),
+            Lightning { damage, range } => cast_lightning(damage, range, objects, fov_map, messages, events),
+            Fireball { damage, radius, range } =>
+                cast_fireball(target, damage, radius, range, objects, fov_map, messages, events),
+            Confuse { duration, range } => cast_confuse(target, duration, range, objects, fov_map, messages),
+            Equip => {
+                let item = inventory.remove(inventory_id);
+                equip(item, inventory, objects, messages, events);
+                return;
+            }
+        };
+        match result {
+            UseResult::UsedUp => {
+                // Destroy after use, unless it was cancelled for some reason
+                let item = take_one(inventory, inventory_id);
+                events.push(Event::UsedItem { name: item.name });
+            }
+            UseResult::Cancelled => {
+                message(messages, "Cancelled", colors::WHITE);
+            }
+        }
+    } else {
+         message(messages,
+                format!("The {} cannot be used.", inventory[inventory_id].name),
+                colors::WHITE);
+    }
+}
+
+fn cast_heal(amount: i32, objects: &mut [Object], messages: &mut Messages) -> UseResult {
+
+    // Heal the player
+    if let Some(fighter) = objects[PLAYER].fighter {
+        if fighter.hp == objects[PLAYER].max_hp() {
+            message(messages, "You are already at full health.", colors::RED);
+            return UseResult::Cancelled;
+        }
+        message(messages, "Your wounds start to feel better!", colors::LIGHT_VIOLET);
+        objects[PLAYER].heal(amount);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+/// Let the player wear an item from the inventory
+fn equip(item: Object, inventory: &mut Vec<Object>, objects: &mut [Object], messages: &mut Messages,
+         events: &mut Vec<Event>) {
+    let name = item.name.clone();
+    match objects[PLAYER].equip(item) {
+        Ok(removed) => {
+            if let Some(removed) = removed {
+                message(messages, format!("You take off the {}.", removed.name), colors::LIGHT_YELLOW);
+                events.push(Event::Unequipped { name: removed.name.clone() });
+                inventory.push(removed);
+            }
+            message(messages, format!("You put on the {}.", name), colors::LIGHT_GREEN);
+            events.push(Event::Equipped { name });
+        }
+        Err(item) => {
+            message(messages, format!("The {} cannot be worn.", name), colors::WHITE);
+            inventory.push(item);
+        }
+    }
+}
+
+fn cast_lightning(damage: i32, range: f32, objects: &mut [Object], fov_map: &FovMap,
+                  messages: &mut Messages, events: &mut Vec<Event>) -> UseResult {
+    // find the closest enemy inside the maximum range and damage it
+    match closest_monster(range, objects, fov_map) {
+        Some(monster_id) => {
+            message(messages,
+                    format!("A lightning bolt strikes the {} with a loud thunder! The damage is {} hit points.",
+                            objects[monster_id].name, damage),
+                    colors::LIGHT_BLUE);
+            damage_by_player(monster_id, damage, objects, messages, events);
+            UseResult::UsedUp
+        }
+        None => {
+            message(messages, "No enemy is close enough to strike.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn cast_fireball(target: Option<(i32, i32)>, damage: i32, radius: f32, range: f32, objects: &mut [Object],
+                 fov_map: &FovMap, messages: &mut Messages, events: &mut Vec<Event>) -> UseResult {
+    let (x, y) = match target {
+        Some((x, y)) if in_target_range((x, y), Some(range), objects, fov_map) => (x, y),
+        _ => {
+            message(messages, "The fireball needs a target in view and in range.", colors::RED);
+            return UseResult::Cancelled;
+        }
+    };
+    message(messages,
+            format!("The fireball explodes, burning everything within {} tiles!", radius),
+            colors::ORANGE);
+
+    // everything in the blast gets burned, including the player
+    for id in 0..objects.len() {
+        if objects[id].fighter.is_some() && objects[id].alive &&
+            objects[id].distance(x, y) <= radius {
+            message(messages,
+                    format!("The {} gets burned for {} hit points.", objects[id].name, damage),
+                    colors::ORANGE);
+            damage_by_player(id, damage, objects, messages, events);
+        }
+    }
+    UseResult::UsedUp
+}
+
+fn cast_confuse(target: Option<(i32, i32)>, duration: i32, range: f32, objects: &mut [Object],
+                fov_map: &FovMap, messages: &mut Messages) -> UseResult {
+    // the monster on the target tile, or the closest one when there is no target
+    let monster_id = match target {
+        Some(tile) if in_target_range(tile, Some(range), objects, fov_map) =>
+            objects.iter().position(|object| object.pos() == tile && object.ai.is_some()),
+        Some(_) => None,
+        None => closest_monster(range, objects, fov_map),
+    };
+    match monster_id {
+        Some(monster_id) => {
+            // replace the monster's AI with a "confused" one, after some turns it will restore the old AI
+            let previous = objects[monster_id].ai.take().expect("a monster has an AI");
+            objects[monster_id].ai = Some(Ai::Confused { previous: Box::new(previous), num_turns: duration });
+            message(messages,
+                    format!("The eyes of the {} look vacant, as it starts to stumble around!",
+                            objects[monster_id].name),
+                    colors::LIGHT_GREEN);
+            UseResult::UsedUp
+        }
+        None => {
+            message(messages, "No enemy is close enough to confuse.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+/// Whether the player can target the tile: in view, and within range if there is one
+pub fn in_target_range((x, y): (i32, i32), range: Option<f32>, objects: &[Object], fov_map: &FovMap) -> bool {
+    fov_map.is_in_fov(x, y) && range.is_none_or(|range| objects[PLAYER].distance(x, y) <= range)
+}
+
+/// Find the closest monster in view of the player, up to a maximum range
+fn closest_monster(max_range: f32, objects: &[Object], fov_map: &FovMap) -> Option<usize> {
+    objects.iter().enumerate()
+        .filter(|&(id, object)| {
+            id != PLAYER && object.fighter.is_some() && object.ai.is_some() &&
+                fov_map.is_in_fov(object.x, object.y)
+        })
+        .map(|(id, object)| (id, object.distance_to(&objects[PLAYER])))
+        .filter(|&(_, distance)| distance <= max_range)
+        .min_by(|&(_, a), &(_, b)| a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal))
+        .map(|(id, _)| id)
+}
+
+/// Damage an object by a spell or thrown item, reporting when it dies
+fn damage_by_player(id: usize, damage: i32, objects: &mut [Object], messages: &mut Messages,
+                    events: &mut Vec<Event>) {
+    let from = objects[PLAYER].pos();
+    objects[id].take_damage(damage, from, messages);
+    if !objects[id].alive {
+        killed(PLAYER, id, objects, messages, events);
+    }
+}
+
+/// Report a death, and give the killer the experience the victim was worth
+fn killed(killer_id: usize, id: usize, objects: &mut [Object], messages: &mut Messages,
+          events: &mut Vec<Event>) {
+    events.push(Event::Died { id });
+    let xp = objects[id].fighter.map_or(0, |f| f.xp);
+    if killer_id == id || xp <= 0 {
+        return;
+    }
+    if let Some(ref mut fighter) = objects[killer_id].fighter {
+        fighter.xp += xp;
+    }
+    if killer_id == PLAYER {
+        message(messages, format!("You gain {} experience points.", xp), colors::YELLOW);
+    }
+}
+
+pub(crate) fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object], events: &mut Vec<Event>) {
+    let (x, y) = objects[id].pos();
+    if !is_blocked(x + dx, y + dy, map, objects) {
+        objects[id].set_pos(x + dx, y + dy);
+        events.push(Event::Moved { id, to: (x + dx, y + dy) });
+    }
+}
+
+/// Let the attacker attack the target, recording what happened
+pub(crate) fn attack(attacker_id: usize, target_id: usize, objects: &mut [Object], messages: &mut Messages,
+          events: &mut Vec<Event>) {
+    let (attacker, target) = mut_two(attacker_id, target_id, objects);
+    let damage = attacker.attack(target, messages);
+    events.push(Event::Attacked { attacker: attacker_id, target: target_id, damage });
+    let (x, y) = target.pos();
+    if !target.alive {
+        killed(attacker_id, target_id, objects, messages, events);
+    }
+    ai::make_noise(x, y, objects);
+}
+
+fn player_move_or_attack(dx: i32, dy: i32, map: &Map, objects: &mut [Object], messages: &mut Messages,
+                         events: &mut Vec<Event>) {
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
+
+    let target_id = objects.iter().position(|object| {
+        object.fighter.is_some() && object.pos() == (x, y)
+    });
+
+    match target_id {
+        // A monster was found
+        Some(id) => {
+            if objects[id].alive {
+                attack(PLAYER, id, objects, messages, events);
+            } else {
+                return;
+            }
+            move_by(PLAYER, dx, dy, map, objects, events);
+        }
+        // No monster was found
+        None => move_by(PLAYER, dx, dy, map, objects, events)
+    }
+}
+
+fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
+    assert_ne!(first_index, second_index);
+    let split_at_index = cmp::max(first_index, second_index);
+    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
+
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use map::{Tile, MAP_HEIGHT, MAP_WIDTH};
+    use object::{DeathCallback, Equippable, Fighter, Item, Slot};
+
+    /// A game on an open map, with the player at (10, 10) and the given objects
+    fn arena(objects: Vec<Object>) -> Game {
+        let mut game = Game::new(1, MapGenerator::Rooms);
+        game.map = vec![vec![Tile::empty(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        game.objects.truncate(1);
+        game.objects[PLAYER].set_pos(10, 10);
+        game.objects.extend(objects);
+        game.fov_map = FovMap::from_map(&game.map);
+        game.compute_fov();
+        game
+    }
+
+    /// A monster that stands still and never hits back
+    fn dummy(x: i32, y: i32, hp: i32) -> Object {
+        let mut dummy = Object::new(x, y, 'd', "dummy", colors::WHITE, true);
+        dummy.alive = true;
+        dummy.fighter = Some(Fighter { base_max_hp: hp, hp, base_defense: 0, base_power: 0, xp: 35,
+                                       on_death: DeathCallback::Monster });
+        dummy
+    }
+
+    fn potion(item: Item) -> Object {
+        let mut potion = Object::new(0, 0, '!', "potion", colors::WHITE, false);
+        potion.item = Some(item);
+        potion
+    }
+
+    fn hp(object: &Object) -> i32 {
+        object.fighter.map_or(0, |fighter| fighter.hp)
+    }
+
+    #[test]
+    fn the_message_log_drops_the_oldest_messages() {
+        let mut messages = Messages::new(3);
+        for text in &["one", "two", "two", "three", "four"] {
+            messages.add(*text, colors::WHITE);
+        }
+        let texts: Vec<_> = messages.iter().map(Message::display_text).collect();
+        assert_eq!(texts, vec!["two x2", "three", "four"]);
+
+        messages.set_capacity(1);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages.last().map(|message| message.text.as_str()), Some("four"));
+    }
+
+    #[test]
+    fn the_seed_determines_the_game() {
+        for &generator in &[MapGenerator::Rooms, MapGenerator::Bsp, MapGenerator::Caves] {
+            let mut first = Game::new(7, generator);
+            let mut second = Game::new(7, generator);
+            for _ in 0..20 {
+                assert_eq!(first.step(Action::Wait), second.step(Action::Wait));
+            }
+            let positions = |game: &Game| game.objects.iter()
+                .map(|object| (object.name.clone(), object.pos()))
+                .collect::<Vec<_>>();
+            assert_eq!(positions(&first), positions(&second));
+        }
+    }
+
+    #[test]
+    fn moving_into_a_monster_attacks_it() {
+        let mut game = arena(vec![dummy(11, 10, 10)]);
+        let events = game.step(Action::Move(1, 0));
+        assert_eq!(events, vec![Event::Attacked { attacker: PLAYER, target: 1, damage: 5 }]);
+        assert_eq!(hp(&game.objects[1]), 5);
+        assert_eq!(game.objects[PLAYER].pos(), (10, 10));
+        assert_eq!(game.turn, 1);
+    }
+
+    #[test]
+    fn killing_a_monster_gives_its_experience() {
+        let mut game = arena(vec![dummy(11, 10, 5)]);
+        let events = game.step(Action::Move(1, 0));
+        assert!(events.contains(&Event::Died { id: 1 }));
+        assert!(!game.objects[1].alive);
+        assert!(!game.objects[1].blocks);
+        assert_eq!(game.objects[PLAYER].fighter.unwrap().xp, 35);
+        // the remains no longer stand in the way, so the player steps onto them
+        assert!(events.contains(&Event::Moved { id: PLAYER, to: (11, 10) }));
+    }
+
+    #[test]
+    fn moves_go_one_tile_and_stop_at_the_border() {
+        let mut game = arena(vec![]);
+        game.step(Action::Move(5, -3));
+        assert_eq!(game.objects[PLAYER].pos(), (11, 9));
+
+        game.objects[PLAYER].set_pos(0, 0);
+        let events = game.step(Action::Move(-1, -1));
+        assert!(events.is_empty());
+        assert_eq!(game.objects[PLAYER].pos(), (0, 0));
+    }
+
+    #[test]
+    fn attacks_deal_power_minus_defense() {
+        let mut messages = Messages::default();
+        let mut player = Object::player();
+        let mut target = dummy(0, 0, 10);
+        assert_eq!(player.attack(&mut target, &mut messages), 5);
+        assert_eq!(hp(&target), 5);
+
+        // the dummy has no power, so it can not get through any defense
+        assert_eq!(target.attack(&mut player, &mut messages), 0);
+        assert_eq!(hp(&player), 30);
+
+        player.take_damage(30, (0, 0), &mut messages);
+        assert!(!player.alive);
+    }
+
+    #[test]
+    fn healing_uses_up_the_potion() {
+        let mut game = arena(vec![]);
+        game.inventory.push(potion(Item::Heal { amount: 4 }));
+        game.objects[PLAYER].take_damage(20, (11, 10), &mut game.messages);
+
+        let events = game.step(Action::UseItem(0, None));
+        assert_eq!(events, vec![Event::UsedItem { name: "potion".into() }]);
+        assert_eq!(hp(&game.objects[PLAYER]), 14);
+        assert!(game.inventory.is_empty());
+    }
+
+    #[test]
+    fn healing_at_full_health_keeps_the_potion() {
+        let mut game = arena(vec![]);
+        game.inventory.push(potion(Item::Heal { amount: 4 }));
+        let events = game.step(Action::UseItem(0, None));
+        assert!(events.is_empty());
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(game.turn, 0);
+    }
+
+    #[test]
+    fn lightning_strikes_the_closest_monster() {
+        let mut near = dummy(13, 10, 10);
+        near.ai = Some(Ai::Asleep);
+        let mut far = dummy(16, 10, 10);
+        far.ai = Some(Ai::Asleep);
+        let mut game = arena(vec![far, near]);
+        game.inventory.push(potion(Item::Lightning { damage: 40, range: 5.0 }));
+
+        let events = game.step(Action::UseItem(0, None));
+        assert!(events.contains(&Event::Died { id: 2 }));
+        assert!(game.objects[1].alive);
+        assert!(game.inventory.is_empty());
+    }
+
+    #[test]
+    fn only_the_stairs_lead_down() {
+        let mut sign = Object::new(10, 10, '>', "stairs", colors::WHITE, false);
+        sign.always_visible = true;
+        let mut game = arena(vec![sign]);
+        assert!(game.step(Action::Descend).is_empty());
+        assert_eq!(game.depth, 1);
+
+        game.objects[1] = Object::stairs(10, 10);
+        assert_eq!(game.step(Action::Descend), vec![Event::Descended { depth: 2 }]);
+        assert_eq!(game.depth, 2);
+        assert_eq!(game.objects.iter().filter(|object| object.stairs).count(), 1);
+    }
+
+    #[test]
+    fn picked_up_items_stack() {
+        let mut first = potion(Item::Heal { amount: 4 });
+        first.set_pos(10, 10);
+        let second = first.clone();
+        let mut game = arena(vec![first, second]);
+
+        assert_eq!(game.step(Action::PickUp), vec![Event::PickedUp { name: "potion".into() }]);
+        game.step(Action::PickUp);
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(game.inventory[0].count, 2);
+        assert_eq!(game.objects.len(), 1);
+    }
+
+    fn gear(name: &str, slot: Slot, power_bonus: i32, defense_bonus: i32, max_hp_bonus: i32) -> Object {
+        let mut gear = Object::new(0, 0, '(', name, colors::WHITE, false);
+        gear.item = Some(Item::Equip);
+        gear.equippable = Some(Equippable { slot, power_bonus, defense_bonus, max_hp_bonus });
+        gear
+    }
+
+    fn worn(game: &Game) -> Vec<String> {
+        game.objects[PLAYER].equipment.as_ref().unwrap().items.iter().map(|item| item.name.clone()).collect()
+    }
+
+    #[test]
+    fn equipment_adds_its_bonuses() {
+        let mut game = arena(vec![]);
+        game.inventory.push(gear("sword", Slot::Weapon, 3, 0, 0));
+        game.inventory.push(gear("shield", Slot::Shield, 0, 1, 0));
+        game.inventory.push(gear("ring", Slot::Ring, 0, 0, 5));
+        for _ in 0..3 {
+            assert_eq!(game.step(Action::UseItem(0, None)).len(), 1);
+        }
+        let player = &game.objects[PLAYER];
+        assert_eq!((player.power(), player.defense(), player.max_hp()), (8, 3, 35));
+        assert!(game.inventory.is_empty());
+        // changing equipment is free, like using any item
+        assert_eq!(game.turn, 0);
+    }
+
+    #[test]
+    fn a_full_slot_gives_up_the_item_put_on_first() {
+        let mut game = arena(vec![]);
+        for name in &["first ring", "second ring", "third ring"] {
+            game.inventory.push(gear(name, Slot::Ring, 0, 0, 1));
+        }
+        game.step(Action::UseItem(0, None));
+        game.step(Action::UseItem(0, None));
+        assert_eq!(worn(&game), vec!["first ring", "second ring"]);
+
+        let events = game.step(Action::UseItem(0, None));
+        assert_eq!(events, vec![Event::Unequipped { name: "first ring".into() },
+                                Event::Equipped { name: "third ring".into() }]);
+        assert_eq!(worn(&game), vec!["second ring", "third ring"]);
+        assert_eq!(game.inventory[0].name, "first ring");
+    }
+
+    #[test]
+    fn taking_off_a_max_hp_bonus_lowers_the_hp() {
+        let mut game = arena(vec![]);
+        game.inventory.push(gear("ring", Slot::Ring, 0, 0, 10));
+        game.step(Action::UseItem(0, None));
+        game.objects[PLAYER].heal(10);
+        assert_eq!(hp(&game.objects[PLAYER]), 40);
+
+        assert_eq!(game.step(Action::Unequip(0)), vec![Event::Unequipped { name: "ring".into() }]);
+        assert_eq!(hp(&game.objects[PLAYER]), 30);
+        assert_eq!(game.objects[PLAYER].max_hp(), 30);
+        assert_eq!(game.inventory.len(), 1);
+    }
+
+    #[test]
+    fn fireballs_burn_everything_in_the_blast() {
+        let mut game = arena(vec![dummy(13, 10, 5), dummy(15, 11, 10), dummy(17, 10, 10)]);
+        game.inventory.push(potion(Item::Fireball { damage: 5, radius: 3.0, range: 8.0 }));
+
+        let events = game.step(Action::UseItem(0, Some((14, 10))));
+        assert!(events.contains(&Event::Died { id: 1 }));
+        assert_eq!(hp(&game.objects[2]), 5);
+        assert_eq!(hp(&game.objects[3]), 5);
+        // the player is 4 tiles away, just outside the blast
+        assert_eq!(hp(&game.objects[PLAYER]), 30);
+        assert!(game.inventory.is_empty());
+    }
+
+    #[test]
+    fn a_fireball_at_the_feet_burns_the_player() {
+        let mut game = arena(vec![]);
+        game.inventory.push(potion(Item::Fireball { damage: 5, radius: 3.0, range: 8.0 }));
+        game.step(Action::UseItem(0, Some((11, 10))));
+        assert_eq!(hp(&game.objects[PLAYER]), 25);
+    }
+
+    #[test]
+    fn a_fireball_needs_a_target_in_range() {
+        let mut game = arena(vec![]);
+        game.inventory.push(potion(Item::Fireball { damage: 5, radius: 3.0, range: 8.0 }));
+        game.step(Action::UseItem(0, None));
+        game.step(Action::UseItem(0, Some((19, 10))));
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(hp(&game.objects[PLAYER]), 30);
+    }
+
+    #[test]
+    fn confusion_wears_off_into_the_old_behaviour() {
+        let mut monster = dummy(12, 10, 10);
+        monster.ai = Some(Ai::Asleep);
+        let mut game = arena(vec![monster]);
+        game.inventory.push(potion(Item::Confuse { duration: 2, range: 8.0 }));
+
+        game.step(Action::UseItem(0, Some((12, 10))));
+        assert_eq!(game.objects[1].ai, Some(Ai::Confused { previous: Box::new(Ai::Asleep), num_turns: 2 }));
+        for _ in 0..2 {
+            game.step(Action::Wait);
+            assert!(matches!(game.objects[1].ai, Some(Ai::Confused { .. })));
+        }
+        game.step(Action::Wait);
+        assert_eq!(game.objects[1].ai, Some(Ai::Asleep));
+    }
+
+    #[test]
+    fn levelling_up_needs_enough_experience() {
+        let mut game = arena(vec![]);
+        assert_eq!(game.level_up_xp(), 350);
+        game.objects[PLAYER].fighter.as_mut().unwrap().xp = 349;
+        assert!(game.step(Action::LevelUp(Stat::Power)).is_empty());
+        assert_eq!(game.objects[PLAYER].level, 1);
+
+        game.objects[PLAYER].fighter.as_mut().unwrap().xp = 360;
+        assert_eq!(game.step(Action::LevelUp(Stat::Power)), vec![Event::LeveledUp { level: 2 }]);
+        assert_eq!(game.objects[PLAYER].fighter.unwrap().xp, 10);
+        assert_eq!(game.level_up_xp(), 500);
+    }
+
+    #[test]
+    fn levelling_up_raises_the_chosen_stat() {
+        for &(stat, expected) in &[(Stat::Hp, (5, 2, 40)), (Stat::Power, (6, 2, 30)), (Stat::Defense, (5, 3, 30))] {
+            let mut game = arena(vec![]);
+            game.objects[PLAYER].fighter.as_mut().unwrap().xp = 350;
+            game.step(Action::LevelUp(stat));
+            let player = &game.objects[PLAYER];
+            assert_eq!((player.power(), player.defense(), player.max_hp()), expected);
+        }
+        let mut game = arena(vec![]);
+        game.objects[PLAYER].fighter.as_mut().unwrap().xp = 350;
+        game.step(Action::LevelUp(Stat::Hp));
+        assert_eq!(hp(&game.objects[PLAYER]), 40);
+    }
+
+    #[test]
+    fn throws_land_on_the_first_monster_in_the_way() {
+        let mut game = arena(vec![dummy(13, 10, 10), dummy(15, 10, 10)]);
+        let mut dagger = potion(Item::Heal { amount: 1 });
+        dagger.throw_damage = Some(4);
+        game.inventory.push(dagger);
+
+        let events = game.step(Action::Throw(0, (16, 10)));
+        assert_eq!(events, vec![Event::Thrown { name: "potion".into(), to: (13, 10) }]);
+        assert_eq!(hp(&game.objects[1]), 6);
+        assert_eq!(hp(&game.objects[2]), 10);
+        assert_eq!(game.objects.last().unwrap().pos(), (13, 10));
+        assert!(game.inventory.is_empty());
+        assert_eq!(game.turn, 1);
+    }
+
+    #[test]
+    fn harmless_throws_bounce_off() {
+        let mut game = arena(vec![dummy(13, 10, 10)]);
+        game.inventory.push(potion(Item::Heal { amount: 1 }));
+        game.step(Action::Throw(0, (13, 10)));
+        assert_eq!(hp(&game.objects[1]), 10);
+        assert_eq!(game.objects.last().unwrap().pos(), (13, 10));
+    }
+
+    #[test]
+    fn throws_stop_in_front_of_walls() {
+        let mut game = arena(vec![]);
+        game.map[14][10] = Tile::wall();
+        assert_eq!(game.throw_path((16, 10)), vec![(11, 10), (12, 10), (13, 10)]);
+        assert_eq!(game.throw_path((13, 13)), vec![(11, 11), (12, 12), (13, 13)]);
+    }
+
+    #[test]
+    fn throws_out_of_range_are_refused() {
+        let mut game = arena(vec![]);
+        game.inventory.push(potion(Item::Heal { amount: 1 }));
+        assert!(game.step(Action::Throw(0, (19, 10))).is_empty());
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(game.turn, 0);
+    }
+
+    #[test]
+    fn dropping_takes_one_off_the_stack() {
+        let mut game = arena(vec![]);
+        let mut potions = potion(Item::Heal { amount: 4 });
+        potions.count = 3;
+        game.inventory.push(potions);
+
+        assert_eq!(game.step(Action::Drop(0)), vec![Event::Dropped { name: "potion".into() }]);
+        assert_eq!(game.inventory[0].count, 2);
+        let dropped = game.objects.last().unwrap();
+        assert_eq!((dropped.pos(), dropped.count), ((10, 10), 1));
+        assert_eq!(game.turn, 1);
+    }
+
+    #[test]
+    fn using_one_of_a_stack_keeps_the_rest() {
+        let mut game = arena(vec![]);
+        let mut potions = potion(Item::Heal { amount: 4 });
+        potions.count = 3;
+        game.inventory.push(potions);
+        game.objects[PLAYER].take_damage(10, (11, 10), &mut game.messages);
+
+        assert_eq!(game.step(Action::UseItem(0, None)), vec![Event::UsedItem { name: "potion".into() }]);
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(game.inventory[0].count, 2);
+    }
+
+    #[test]
+    fn looking_describes_what_the_player_knows() {
+        let mut monster = dummy(12, 10, 10);
+        monster.ai = Some(Ai::Asleep);
+        let mut game = arena(vec![monster]);
+        game.map[12][11] = Tile::wall();
+        game.fov_map = FovMap::from_map(&game.map);
+        game.compute_fov();
+
+        assert_eq!(game.describe(12, 10), vec!["The floor.", "dummy: unhurt, asleep"]);
+        assert_eq!(game.describe(12, 11), vec!["A wall."]);
+        assert_eq!(game.describe(70, 40), vec!["You have not explored this place yet."]);
+        assert!(game.describe(-1, 10).is_empty());
+
+        // out of view, only the map is remembered
+        game.objects[PLAYER].set_pos(40, 30);
+        game.compute_fov();
+        assert_eq!(game.describe(12, 10), vec!["The floor, as you remember it."]);
+    }
+}
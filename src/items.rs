@@ -0,0 +1,53 @@
+//! Item templates, the kinds of items that lie around in the dungeon.
+
+use colors::Color;
+use object::{Equippable, Item, Object};
+use spawn::Spawn;
+
+/// A kind of item, as described in the item data file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ItemTemplate {
+    pub name: String,
+    pub glyph: char,
+    pub color: Color,
+    /// What using it does
+    pub effect: Item,
+    /// Where it is worn and the bonuses it gives, for items with the equip effect
+    #[serde(default)]
+    pub equip: Option<Equippable>,
+    /// The damage it does when thrown, None when it bounces off
+    #[serde(default)]
+    pub throw_damage: Option<i32>,
+    /// Where and how often it turns up
+    pub spawn: Spawn,
+}
+
+impl ItemTemplate {
+    /// Check its name, the numbers of its effect, that only items with the equip effect have an
+    /// equip entry and that a thrown item hurts. Returns the reason the template is invalid
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("the name is empty".into());
+        }
+        self.effect.validate()?;
+        match (self.effect, self.equip) {
+            (Item::Equip, None) => return Err("an item with the equip effect needs an equip entry".into()),
+            (Item::Equip, Some(_)) | (_, None) => {}
+            (_, Some(_)) => return Err("only items with the equip effect can have an equip entry".into()),
+        }
+        if self.throw_damage.is_some_and(|damage| damage <= 0) {
+            return Err("throw_damage must be positive".into());
+        }
+        self.spawn.validate()
+    }
+
+    /// A new item of this kind, lying on the floor
+    pub fn create(&self, x: i32, y: i32) -> Object {
+        let mut item = Object::new(x, y, self.glyph, &self.name, self.color, false);
+        item.item = Some(self.effect);
+        item.equippable = self.equip;
+        item.throw_damage = self.throw_damage;
+        item
+    }
+}
@@ -0,0 +1,321 @@
+//! Key bindings: which keys trigger which game commands.
+//!
+//! A keymap file is JSON mapping command names to lists of keys, for instance
+//! `{ "pick_up": ["g", ","], "look": ["x"] }`. The commands it lists replace their default
+//! keys, all other commands keep them. A key is a single character, as it is typed, or the
+//! name of a special key like `up` or `numpad8`, optionally prefixed with `ctrl+` or `alt+`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde_json;
+
+/// Something the player can do with a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    MoveNorth,
+    MoveSouth,
+    MoveWest,
+    MoveEast,
+    MoveNorthWest,
+    MoveNorthEast,
+    MoveSouthWest,
+    MoveSouthEast,
+    Wait,
+    PickUp,
+    Inventory,
+    Drop,
+    Throw,
+    Descend,
+    Character,
+    Look,
+    MessageLog,
+    Fullscreen,
+    Quit,
+}
+
+impl Command {
+    /// The offset a movement command steps by
+    pub fn offset(self) -> Option<(i32, i32)> {
+        use self::Command::*;
+        match self {
+            MoveNorth => Some((0, -1)),
+            MoveSouth => Some((0, 1)),
+            MoveWest => Some((-1, 0)),
+            MoveEast => Some((1, 0)),
+            MoveNorthWest => Some((-1, -1)),
+            MoveNorthEast => Some((1, -1)),
+            MoveSouthWest => Some((-1, 1)),
+            MoveSouthEast => Some((1, 1)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    /// The name of the command in a keymap file
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut name = String::new();
+        for c in format!("{:?}", self).chars() {
+            if c.is_uppercase() && !name.is_empty() {
+                name.push('_');
+            }
+            name.extend(c.to_lowercase());
+        }
+        write!(f, "{}", name)
+    }
+}
+
+/// The keys every command has when the keymap file does not rebind it
+const DEFAULT_BINDINGS: &[(Command, &[&str])] = &[
+    (Command::MoveNorth, &["up", "numpad8", "k"]),
+    (Command::MoveSouth, &["down", "numpad2", "j"]),
+    (Command::MoveWest, &["left", "numpad4", "h"]),
+    (Command::MoveEast, &["right", "numpad6", "l"]),
+    (Command::MoveNorthWest, &["home", "numpad7", "y"]),
+    (Command::MoveNorthEast, &["pageup", "numpad9", "u"]),
+    (Command::MoveSouthWest, &["end", "numpad1", "b"]),
+    (Command::MoveSouthEast, &["pagedown", "numpad3", "n"]),
+    (Command::Wait, &[".", "numpad5"]),
+    (Command::PickUp, &["g"]),
+    (Command::Inventory, &["i"]),
+    (Command::Drop, &["d"]),
+    (Command::Throw, &["t"]),
+    (Command::Descend, &[">"]),
+    (Command::Character, &["c"]),
+    (Command::Look, &["x"]),
+    (Command::MessageLog, &["m"]),
+    (Command::Fullscreen, &["ctrl+enter"]),
+    (Command::Quit, &["escape"]),
+];
+
+/// The names of the keys that do not type a character
+pub const SPECIAL_KEYS: &[&str] = &[
+    "escape", "backspace", "tab", "enter", "space", "insert", "delete",
+    "up", "down", "left", "right", "home", "end", "pageup", "pagedown",
+    "numpad0", "numpad1", "numpad2", "numpad3", "numpad4",
+    "numpad5", "numpad6", "numpad7", "numpad8", "numpad9",
+    "numpadadd", "numpadsubtract", "numpadmultiply", "numpaddivide", "numpaddecimal", "numpadenter",
+    "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9", "f10", "f11", "f12",
+];
+
+/// A key together with the modifiers held down with it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct KeyPress {
+    /// A typed character, or one of the `SPECIAL_KEYS`
+    pub key: String,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl KeyPress {
+    pub fn new<T: Into<String>>(key: T, ctrl: bool, alt: bool) -> Self {
+        KeyPress { key: key.into(), ctrl, alt }
+    }
+
+    /// Read a key as written in a keymap file, like `g`, `numpad8` or `ctrl+enter`
+    pub fn parse(text: &str) -> Option<KeyPress> {
+        let mut press = KeyPress::new("", false, false);
+        let mut rest = text;
+        loop {
+            // a lone "+" is a key of its own, not a modifier
+            let lowered = rest.to_lowercase();
+            if lowered.starts_with("ctrl+") && rest.len() > 5 {
+                press.ctrl = true;
+                rest = &rest[5..];
+            } else if lowered.starts_with("alt+") && rest.len() > 4 {
+                press.alt = true;
+                rest = &rest[4..];
+            } else {
+                break;
+            }
+        }
+        if rest == " " {
+            press.key = "space".into();
+        } else if rest.chars().count() == 1 {
+            // characters are matched as typed, so "G" is a different key than "g"
+            press.key = rest.into();
+        } else if SPECIAL_KEYS.contains(&rest.to_lowercase().as_str()) {
+            press.key = rest.to_lowercase();
+        } else {
+            return None;
+        }
+        Some(press)
+    }
+}
+
+impl fmt::Display for KeyPress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "alt+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    /// A key in the file that is neither a character nor a special key
+    UnknownKey { command: Command, key: String },
+    /// Keys that are bound to more than one command
+    Conflicts(Vec<(KeyPress, Command, Command)>),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeymapError::Io(ref err) => write!(f, "Could not read the keymap: {}", err),
+            KeymapError::Format(ref err) => write!(f, "The keymap is not valid: {}", err),
+            KeymapError::UnknownKey { command, ref key } =>
+                write!(f, "The keymap binds an unknown key \"{}\" to {}", key, command),
+            KeymapError::Conflicts(ref conflicts) => {
+                write!(f, "The keymap binds keys to more than one command:")?;
+                for &(ref key, first, second) in conflicts {
+                    write!(f, "\n  \"{}\" is bound to both {} and {}", key, first, second)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for KeymapError {}
+
+impl From<io::Error> for KeymapError {
+    fn from(err: io::Error) -> Self {
+        KeymapError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KeymapError {
+    fn from(err: serde_json::Error) -> Self {
+        KeymapError::Format(err)
+    }
+}
+
+/// Which command each key triggers
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    commands: HashMap<KeyPress, Command>,
+}
+
+impl Keymap {
+    /// Load the keymap file at the path on top of the default bindings
+    pub fn load(path: &Path) -> Result<Self, KeymapError> {
+        let rebound: BTreeMap<Command, Vec<String>> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Keymap::with_bindings(rebound)
+    }
+
+    /// The default bindings, with the keys of the given commands replaced
+    pub fn with_bindings(rebound: BTreeMap<Command, Vec<String>>) -> Result<Self, KeymapError> {
+        let mut bindings: BTreeMap<Command, Vec<String>> = DEFAULT_BINDINGS.iter()
+            .map(|&(command, keys)| (command, keys.iter().map(|&key| key.into()).collect()))
+            .collect();
+        bindings.extend(rebound);
+
+        let mut commands = HashMap::new();
+        let mut conflicts = vec![];
+        for (&command, keys) in &bindings {
+            for key in keys {
+                let press = match KeyPress::parse(key) {
+                    Some(press) => press,
+                    None => return Err(KeymapError::UnknownKey { command, key: key.clone() }),
+                };
+                match commands.get(&press) {
+                    Some(&other) if other != command => conflicts.push((press.clone(), other, command)),
+                    _ => {}
+                }
+                commands.entry(press).or_insert(command);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(KeymapError::Conflicts(conflicts));
+        }
+        Ok(Keymap { commands })
+    }
+
+    /// The command bound to a key press
+    pub fn command(&self, press: &KeyPress) -> Option<Command> {
+        self.commands.get(press).cloned()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::with_bindings(BTreeMap::new()).expect("the default keymap is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rebind(command: Command, keys: &[&str]) -> Result<Keymap, KeymapError> {
+        let mut rebound = BTreeMap::new();
+        rebound.insert(command, keys.iter().map(|&key| key.into()).collect());
+        Keymap::with_bindings(rebound)
+    }
+
+    fn command(keymap: &Keymap, key: &str) -> Option<Command> {
+        keymap.command(&KeyPress::parse(key).unwrap())
+    }
+
+    #[test]
+    fn keys_are_parsed_with_their_modifiers() {
+        assert_eq!(KeyPress::parse("Ctrl+Alt+x"), Some(KeyPress::new("x", true, true)));
+        assert_eq!(KeyPress::parse("ctrl++"), Some(KeyPress::new("+", true, false)));
+        assert_eq!(KeyPress::parse("+"), Some(KeyPress::new("+", false, false)));
+        assert_eq!(KeyPress::parse(" "), Some(KeyPress::new("space", false, false)));
+        assert_eq!(KeyPress::parse("NumPad8"), Some(KeyPress::new("numpad8", false, false)));
+        assert_eq!(KeyPress::parse("ctrl+"), None);
+        assert_eq!(KeyPress::parse("shift+a"), None);
+    }
+
+    #[test]
+    fn the_default_bindings_are_used_without_a_file() {
+        let keymap = Keymap::default();
+        assert_eq!(command(&keymap, "k"), Some(Command::MoveNorth));
+        assert_eq!(command(&keymap, "ctrl+enter"), Some(Command::Fullscreen));
+        // characters are matched as typed
+        assert_eq!(command(&keymap, "K"), None);
+    }
+
+    #[test]
+    fn rebinding_a_command_replaces_its_keys() {
+        let keymap = rebind(Command::Wait, &["w", "space"]).unwrap();
+        assert_eq!(command(&keymap, "w"), Some(Command::Wait));
+        assert_eq!(command(&keymap, " "), Some(Command::Wait));
+        assert_eq!(command(&keymap, "."), None);
+        assert_eq!(command(&keymap, "g"), Some(Command::PickUp));
+    }
+
+    #[test]
+    fn a_key_bound_twice_is_a_conflict() {
+        match rebind(Command::PickUp, &["g", "d"]) {
+            Err(KeymapError::Conflicts(conflicts)) => {
+                assert_eq!(conflicts, vec![(KeyPress::new("d", false, false), Command::PickUp, Command::Drop)]);
+            }
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_name_their_command() {
+        match rebind(Command::MessageLog, &["shift+m"]) {
+            Err(err @ KeymapError::UnknownKey { .. }) => {
+                assert_eq!(err.to_string(), "The keymap binds an unknown key \"shift+m\" to message_log");
+            }
+            other => panic!("expected an unknown key, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Core of the roguelike: the map, the objects living in it and the rules of the game.
+//!
+//! This crate does not depend on tcod, so the game can be played (and tested) without a
+//! window. The tcod frontend lives in `main.rs`.
+
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+pub mod ai;
+pub mod colors;
+pub mod data;
+pub mod fov;
+pub mod game;
+pub mod items;
+pub mod keymap;
+pub mod map;
+pub mod monsters;
+pub mod object;
+pub mod path;
+pub mod save;
+pub mod settings;
+pub mod spawn;
@@ -1,693 +1,724 @@
 extern crate tcod;
-extern crate rand;
+extern crate roguelike;
 
 use tcod::console::*;
 use tcod::colors;
 use tcod::Color;
-use tcod::map::{FovAlgorithm, Map as FovMap};
-use rand::Rng;
-use std::cmp;
 
 use tcod::input::{self, Event, Key, Mouse};
 
-// Screen globals
-const SCREEN_WIDTH: i32 = 100;
-const SCREEN_HEIGHT: i32 = 60;
-const LIMIT_FPS: i32 = 20;
+use std::cmp::{self, Ordering};
+use std::fs;
+use std::path::Path;
+
+use roguelike::colors::{self as game_colors, Color as GameColor};
+use roguelike::data::GameData;
+use roguelike::fov::FovMap;
+use roguelike::keymap::{Command, KeyPress, Keymap, KeymapError};
+use roguelike::game::{self, message, Action, Game, Stat, LEVEL_UP_DEFENSE, LEVEL_UP_HP, LEVEL_UP_POWER,
+                      MSG_HEIGHT, THROW_RANGE};
+use roguelike::map::{MapGenerator, MAP_HEIGHT, MAP_WIDTH};
+use roguelike::object::{Object, Slot, Targeting, PLAYER};
+use roguelike::save::{load_game, save_game};
+use roguelike::settings::{self, Settings, SettingsError};
+
 // Sizes and coordinates relevant for the GUI
 const BAR_WIDTH: i32 = 20;
-const PANEL_HEIGHT: i32 = 7;
-const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+const PANEL_HEIGHT: i32 = MSG_HEIGHT as i32 + 1;
 const INVENTORY_WIDTH: i32 = 50;
+const MAIN_MENU_WIDTH: i32 = 24;
+const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+// Options on a menu page, one for every letter
+const MENU_LETTERS: i32 = 26;
 // Message log constants
 const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
-const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
-
-type Messages = Vec<(String, Color)>;
 
-// Map properties
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
+// Save game location
+const SAVE_FILE: &str = "savegame";
+// Display and message log settings, if the file exists
+const SETTINGS_FILE: &str = "settings.json";
+// The command line flags that override the settings
+const SETTING_FLAGS: &[&str] = &["--font", "--font-layout", "--font-type", "--screen-width", "--screen-height", "--fps",
+                                 "--message-history"];
+// The command line flags that pick the game to play
+const GAME_FLAGS: &[&str] = &["--seed", "--generator", "--spawn-table"];
+// Data files that replace the built in monsters, items and spawn tables
+const DATA_DIR: &str = "data";
+// Key bindings that replace the default ones, if the file exists
+const KEYMAP_FILE: &str = "keymap.json";
 
 // Wall properties
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
 const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
-
-// Room properties
-const ROOM_MAX_SIZE: i32 = 10;
-const ROOM_MIN_SIZE: i32 = 10;
-const MAX_ROOMS: i32 = 10;
-const MAX_ROOM_ITEMS: i32 = 2;
-
-// Fov algo
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
-const FOV_LIGHT_WALLS: bool = true;
-const TORCH_RADIUS: i32 = 10;
-
-// Monster stuff
-const MAX_ROOM_MONSTERS: i32 = 3;
-
-// Healing
-const HEAL_AMOUNT : i32 = 4;
-
-// The player index
-const PLAYER: usize = 0;
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum DeathCallback {
-    Player,
-    Monster,
+// Multiplied into the tiles in view that are out of range while targeting
+const COLOR_OUT_OF_RANGE: Color = Color { r: 110, g: 110, b: 110 };
+// The highlighted option of a menu
+const COLOR_MENU_SELECTED: Color = Color { r: 60, g: 60, b: 110 };
+// The tiles a thrown item passes
+const COLOR_FLIGHT: Color = Color { r: 0, g: 127, b: 127 };
+
+/// The tcod consoles, input state and key bindings
+struct Tcod {
+    root: Root,
+    con: Offscreen,
+    panel: Offscreen,
+    mouse: Mouse,
+    keymap: Keymap,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum Item {
-    Heal,
-}
-
-impl DeathCallback {
-    fn callback(self, object: &mut Object) {
-        use DeathCallback::*;
-        let callback: fn(&mut Object) = match self {
-            Player => player_death,
-            Monster => monster_death,
-        };
-        callback(object);
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-struct Fighter {
-    max_hp: i32,
-    hp: i32,
-    defense: i32,
-    power: i32,
-    on_death: DeathCallback,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-struct Ai;
-
-
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
+    Continue,
     Exit,
 }
 
-enum UseResult {
-    UsedUp,
-    Cancelled
-}
-
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
-}
-
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
-    }
-
-    pub fn center(&self) -> (i32, i32) {
-        let center_x = (self.x1 + self.x2) / 2;
-        let center_y = (self.y1 + self.y2) / 2;
-        (center_x, center_y)
-    }
-
-    pub fn intersects_with(&self, other: &Rect) -> bool {
-        // returns true if this rectangle intersects wit another one
-        (self.x1 <= other.x2) && (self.x2 >= other.x1) &&
-            (self.y1 <= other.y2) && (self.y2 >= other.y1)
-    }
-}
-
-fn create_room(room: Rect, map: &mut Map) {
-    for x in (room.x1 + 1)..room.x2 {
-        for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
-        }
-    }
-}
-
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
-    }
-}
-
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    let y_min = cmp::min(y1, y2);
-    let y_max = cmp::max(y1, y2);
-    for y in y_min..y_max + 1 {
-        map[x as usize][y as usize] = Tile::empty();
-    }
-}
-
-#[derive(Copy, Clone, Debug)]
-pub struct Tile {
-    blocked: bool,
-    block_sight: bool,
-    explored: bool,
+/// Convert a color of the game core to a tcod color
+fn tcod_color(color: GameColor) -> Color {
+    Color { r: color.r, g: color.g, b: color.b }
 }
 
-type Map = Vec<Vec<Tile>>;
-
-impl Tile {
-    pub fn empty() -> Self {
-        Tile { blocked: false, explored: false, block_sight: false }
-    }
-
-    pub fn wall() -> Self {
-        Tile { blocked: true, explored: false, block_sight: true }
-    }
+/// Drawing of game objects on a tcod console
+trait Draw {
+    fn draw(&self, con: &mut dyn Console);
+    fn clear(&self, con: &mut dyn Console);
 }
 
-fn make_map(objects: &mut Vec<Object>) -> Map {
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
-    let mut rooms: Vec<Rect> = vec![];
-    let mut starting_position = (0, 0);
-    let mut player = Object::new(starting_position.0, starting_position.1, '@', "player", colors::WHITE, true);
-    player.alive = true;
-    player.fighter = Some(Fighter { max_hp: 30, hp: 30, defense: 2, power: 5, on_death: DeathCallback::Player });
-
-    objects.push(player);
-    for room_idx in 0..MAX_ROOMS {
-
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
-
-        let new_room = Rect::new(x, y, w, h);
-
-        let failed = rooms.iter().any(|other_room| new_room.intersects_with(other_room));
-
-        if !failed {
-            create_room(new_room, &mut map);
-            // Add some content to the this room, such as monsters
-            place_objects(new_room, &mut map, objects);
-
-            let (new_x, new_y) = new_room.center();
-
-            if rooms.is_empty() {
-                starting_position = (new_x, new_y);
-            } else {
-                // all rooms after the first:
-                // connect it to the previous room with a tunnel
-
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
-
-                // toss a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
-            }
-            rooms.push(new_room);
-        }
-    }
-    objects[PLAYER].set_pos(starting_position.0, starting_position.1);
-    map
-}
-
-/// An object in the game
-#[derive(Debug)]
-pub struct Object {
-    x: i32,
-    y: i32,
-    char: char,
-    color: Color,
-    name: String,
-    blocks: bool,
-    alive: bool,
-    fighter: Option<Fighter>,
-    ai: Option<Ai>,
-    item: Option<Item>,
-}
-
-/// Implementation of the object
-impl Object {
-    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color, blocks: bool) -> Self {
-        Object {
-            x,
-            y,
-            char,
-            color,
-            name: name.into(),
-            blocks,
-            alive: false,
-            fighter: None,
-            ai: None,
-            item: None,
-        }
-    }
-
-
+impl Draw for Object {
     /// Set the color and then draw the character that represents this object at its position
-    pub fn draw(&self, con: &mut Console) {
-        con.set_default_foreground(self.color);
+    fn draw(&self, con: &mut dyn Console) {
+        con.set_default_foreground(tcod_color(self.color));
         con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
     }
 
     /// Erase the character that represents this object
-    pub fn clear(&self, con: &mut Console) {
+    fn clear(&self, con: &mut dyn Console) {
         con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
     }
+}
 
-    pub fn pos(&self) -> (i32, i32) {
-        (self.x, self.y)
-    }
-
-    pub fn set_pos(&mut self, x: i32, y: i32) {
-        self.x = x;
-        self.y = y;
-    }
+/// Show a menu and wait for a choice: the letter next to an option, or moving the highlight
+/// with the arrow keys and Enter. Options that do not fit on the screen or beyond the letters
+/// go on further pages, browsed with PgUp/PgDn or by moving past the edge of the page.
+/// Any other key cancels and returns None.
+fn menu<T: AsRef<str>>(header: &str,
+                       options: &[T],
+                       width: i32,
+                       root: &mut Root) -> Option<usize> {
+    use tcod::input::KeyCode::*;
 
-    pub fn take_damage(&mut self, damage: i32) {
-        // apply damage if possible
-        if let Some(fighter) = self.fighter.as_mut() {
-            if damage > 0 {
-                fighter.hp -= damage;
+    // Calculate total height for the header (after auto-wrap), what is left is for the options
+    // and a line with the page number
+    let header_height = root.get_height_rect(0, 0, width, root.height(), header);
+    let page_size = (root.height() - header_height - 1).clamp(1, MENU_LETTERS) as usize;
+    let pages = cmp::max(1, options.len().div_ceil(page_size));
+    let mut selected = 0;
+
+    loop {
+        let first = selected / page_size * page_size;
+        let shown = &options[first..cmp::min(first + page_size, options.len())];
+        let footer_height = if pages > 1 { 1 } else { 0 };
+        let height = header_height + shown.len() as i32 + footer_height;
+
+        let mut window = Offscreen::new(width, height);
+        window.set_default_foreground(colors::WHITE);
+        window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, header);
+
+        for (index, option_text) in shown.iter().enumerate() {
+            let y = header_height + index as i32;
+            if first + index == selected {
+                window.set_default_background(COLOR_MENU_SELECTED);
+                window.rect(0, y, width, 1, false, BackgroundFlag::Set);
             }
+            let menu_letter = (b'a' + index as u8) as char;
+            let text = format!("({}) {}", menu_letter, option_text.as_ref());
+            window.print_ex(0, y, BackgroundFlag::None, TextAlignment::Left, text);
         }
-
-        // check for death, call the death function
-        if let Some(fighter) = self.fighter {
-            if fighter.hp <= 0 {
-                self.alive = false;
-                fighter.on_death.callback(self);
-            }
+        if pages > 1 {
+            window.set_default_foreground(colors::LIGHT_GREY);
+            window.print_ex(0, height - 1, BackgroundFlag::None, TextAlignment::Left,
+                            format!("Page {}/{}, PgUp/PgDn for more", first / page_size + 1, pages));
         }
-    }
 
-    pub fn attack(&mut self, target: &mut Object, messages: &mut Messages) {
-        // a simple formula for attack damage
-        let damage = self.fighter.map_or(0, |f| f.power) - target.fighter.map_or(0, |f| f.defense);
-        if damage > 0 {
-            // Make the target take some damage
-            message(messages, format!("{} attacks {} for {} hit points", self.name, target.name, damage), self.color);
-            target.take_damage(damage);
-        } else {
-            message(messages, format!("{} attack {} but it has no effect!", self.name, target.name), colors::DARK_YELLOW);
+        // blit the contents of "window" to the root console
+        let x = root.width() / 2 - width / 2;
+        let y = root.height() / 2 - height / 2;
+        tcod::console::blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+
+        // Present the root console to the player and wait for a key-press
+        root.flush();
+        let key = root.wait_for_keypress(true);
+        if root.window_closed() || options.is_empty() {
+            return None;
         }
-    }
 
-    pub fn heal(&mut self, amount: i32) {
-        if let Some(ref mut figher) = self.fighter {
-            figher.hp += amount;
-            if figher.hp > figher.max_hp {
-                figher.hp = figher.max_hp;
+        let last = options.len() - 1;
+        match key.code {
+            Up => selected = if selected == 0 { last } else { selected - 1 },
+            Down => selected = if selected == last { 0 } else { selected + 1 },
+            PageUp | Left => selected = selected.saturating_sub(page_size),
+            PageDown | Right => selected = cmp::min(selected + page_size, last),
+            Enter | NumPadEnter => return Some(selected),
+            // modifiers on their own do not cancel, they may be part of a letter
+            Shift | Control | Alt => {}
+            _ => {
+                // Convert the ASCII code to an index; if it corresponds to an option on this page return it
+                if key.printable.is_ascii_alphabetic() {
+                    let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
+                    if index < shown.len() {
+                        return Some(first + index);
+                    }
+                }
+                return None;
             }
         }
     }
-
-    pub fn distance_to(&self, other: &Object) -> f32 {
-        let dx = other.x - self.x;
-        let dy = other.y - self.y;
-        ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
-    }
 }
 
-fn player_death(player: &mut Object) {
-    // The game ended
-    println!("You died!");
-    player.char = '%';
-    player.color = colors::DARK_RED;
+/// What the player picked in the inventory menu
+enum InventoryChoice {
+    /// An index in the inventory
+    Carried(usize),
+    /// An index in the player's equipment
+    Worn(usize),
 }
 
-fn pick_item_up(object_id: usize,
-                objects: &mut Vec<Object>,
-                inventory: &mut Vec<Object>,
-                messages: &mut Messages) {
-    if inventory.len() >= 26 {
-        message(messages, format!("Your inventory is full, cannot pick up {}",
-                                  objects[object_id].name), colors::RED);
-    } else {
-        let item = objects.swap_remove(object_id);
-        message(messages, format!("You picked uo a {}", item.name), colors::GREEN);
-        inventory.push(item);
+fn inventory_menu(game: &Game, header: &str, root: &mut Root) -> Option<InventoryChoice> {
+    // How a menu with each item of the invetory as an option, followed by the worn ones
+    let worn: &[Object] = game.objects[PLAYER].equipment.as_ref().map_or(&[], |equipment| &equipment.items);
+    let mut options: Vec<String> = game.inventory.iter().map(|item| item.display_name()).collect();
+    options.extend(worn.iter().map(|item| {
+        let slot = item.equippable.map_or(String::new(), |e| format!("{:?}", e.slot).to_lowercase());
+        format!("{} (worn on {})", item.name, slot)
+    }));
+    if options.is_empty() {
+        options.push("Inventory is empty".into());
     }
-}
 
-fn use_item(inventory_id: usize, inventory: &mut Vec<Object>, objects: &mut [Object],
-            messages: &mut Messages) {
-    use Item::*;
-    // Just call the use_function if it is defined
-    if let Some(item) = inventory[inventory_id].item {
-        let on_use = match item {
-            Heal => cast_heal,
-        };
-        match on_use(inventory_id, objects, messages) {
-            UseResult::UsedUp => {
-                // Destroy after use, unless it was cancelled for some reason
-                inventory.remove(inventory_id);
-            }
-            UseResult::Cancelled => {
-                message(messages, "Cancelled", colors::WHITE);
-            }
-        }
+    let index = menu(header, &options, INVENTORY_WIDTH, root)?;
+
+    // If an item was chosen, return it
+    if index < game.inventory.len() {
+        Some(InventoryChoice::Carried(index))
+    } else if index - game.inventory.len() < worn.len() {
+        Some(InventoryChoice::Worn(index - game.inventory.len()))
     } else {
-         message(messages,
-                format!("The {} cannot be used.", inventory[inventory_id].name),
-                colors::WHITE);
+        None
     }
 }
 
-fn cast_heal(_inventory_id: usize, objects: &mut [Object], messages: &mut Messages) -> UseResult {
-
-    // Heal the player
-    if let Some(fighter) = objects[PLAYER].fighter {
-        if fighter.hp == fighter.max_hp {
-            message(messages, "You are already at full health.", colors::RED);
-            return UseResult::Cancelled;
+/// Show the stats of the player, and what it is wearing
+fn character_screen(game: &Game, root: &mut Root) {
+    let player = &game.objects[PLAYER];
+    let fighter = match player.fighter {
+        Some(fighter) => fighter,
+        None => return,
+    };
+    let mut text = format!("Character information\n\nLevel: {}\nExperience: {}\nExperience to level up: {}\n\n\
+                            Maximum HP: {} ({} base)\nAttack: {} ({} base)\nDefense: {} ({} base)\n\n\
+                            Equipment:",
+                           player.level, fighter.xp, game.level_up_xp(),
+                           player.max_hp(), fighter.base_max_hp, player.power(), fighter.base_power,
+                           player.defense(), fighter.base_defense);
+    if let Some(ref equipment) = player.equipment {
+        for &slot in &[Slot::Weapon, Slot::Shield, Slot::Armor, Slot::Head, Slot::Ring] {
+            let names: Vec<&str> = equipment.in_slot(slot).map(|item| item.name.as_str()).collect();
+            let names = if names.is_empty() { "-".into() } else { names.join(", ") };
+            text.push_str(&format!("\n{:?}: {}", slot, names));
         }
-        message(messages, "Your wounds start to feel better!", colors::LIGHT_VIOLET);
-        objects[PLAYER].heal(HEAL_AMOUNT);
-        return UseResult::UsedUp;
-    }
-    UseResult::Cancelled
-}
-
-fn monster_death(monster: &mut Object) {
-    // Transform it into a nasty corpse, it doesn't block, can't be attacked
-    // and doesn't move
-    println!("{} is dead!", monster.name);
-    monster.char = '%';
-    monster.color = colors::DARK_RED;
-    monster.blocks = false;
-    monster.ai = None;
-    monster.alive = false;
-    monster.name = format!("remains of {}", monster.name);
-}
-
-pub fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
-    let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
-        objects[id].set_pos(x + dx, y + dy);
     }
+    text.push('\n');
+    msgbox(&text, CHARACTER_SCREEN_WIDTH, root);
 }
 
-pub fn player_move_or_attack(dx: i32, dy: i32, map: &Map, objects: &mut [Object], messages: &mut Messages) {
-    let x = objects[PLAYER].x + dx;
-    let y = objects[PLAYER].y + dy;
+/// Show a cursor over the map to let the player pick a tile in view, and within range if
+/// there is one. The cursor follows the mouse or moves with the movement keys, Tab jumps to the
+/// next monster in range. A left-click or Enter picks the tile, a right-click or Escape
+/// cancels and returns None. With `throwing` the flight of a thrown item is drawn as well.
+fn target_tile(tcod: &mut Tcod, game: &Game, range: Option<f32>, throwing: bool) -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::*;
 
-    let target_id = objects.iter().position(|object| {
-        object.fighter.is_some() && object.pos() == (x, y)
+    let in_range = |tile: (i32, i32)| game::in_target_range(tile, range, &game.objects, &game.fov_map);
+    // the monsters that can be targeted, closest first
+    let mut monsters: Vec<&Object> = game.objects.iter()
+        .filter(|object| object.ai.is_some() && in_range(object.pos()))
+        .collect();
+    monsters.sort_by(|a, b| {
+        let player = &game.objects[PLAYER];
+        a.distance_to(player).partial_cmp(&b.distance_to(player)).unwrap_or(Ordering::Equal)
     });
+    let monsters: Vec<(i32, i32)> = monsters.iter().map(|monster| monster.pos()).collect();
+
+    let mut cursor = monsters.first().cloned().unwrap_or_else(|| game.objects[PLAYER].pos());
+    // ignore clicks made before targeting started
+    tcod.mouse.lbutton_pressed = false;
+    tcod.mouse.rbutton_pressed = false;
+
+    let target = loop {
+        render_all(tcod, game, false);
+        let flight = if throwing { game.throw_path(cursor) } else { vec![] };
+        draw_targeting(&mut tcod.root, game, cursor, range, in_range(cursor), &flight);
+        tcod.root.flush();
+        for object in &game.objects {
+            object.clear(&mut tcod.con);
+        }
+        if tcod.root.window_closed() {
+            break None;
+        }
 
-    match target_id {
-        // A monster was found
-        Some(id) => {
-            {
-                let (player, target) = mut_two(PLAYER, id, objects);
-                if target.alive {
-                    player.attack(target, messages);
-                } else {
-                    return;
+        match input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1) {
+            Some(Event::Mouse(mouse)) => {
+                tcod.mouse = mouse;
+                let tile = (mouse.cx as i32, mouse.cy as i32);
+                if tile.0 < MAP_WIDTH && tile.1 < MAP_HEIGHT {
+                    // the cursor follows the mouse over the map
+                    cursor = tile;
+                    if mouse.lbutton_pressed && in_range(tile) {
+                        break Some(tile);
+                    }
+                }
+                if mouse.rbutton_pressed {
+                    break None;
                 }
             }
-            move_by(PLAYER, dx, dy, map, objects);
+            Some(Event::Key(key)) => {
+                let (dx, dy) = match (direction(&tcod.keymap, key), key.code) {
+                    (Some(offset), _) => offset,
+                    (None, Tab) => {
+                        // jump to the monster after the one under the cursor
+                        let next = monsters.iter().position(|&tile| tile == cursor).map_or(0, |index| index + 1);
+                        if let Some(&tile) = monsters.get(next).or_else(|| monsters.first()) {
+                            cursor = tile;
+                        }
+                        (0, 0)
+                    }
+                    (None, Enter) | (None, NumPadEnter) if in_range(cursor) => break Some(cursor),
+                    (None, Escape) => break None,
+                    _ => (0, 0),
+                };
+                cursor = ((cursor.0 + dx).clamp(0, MAP_WIDTH - 1), (cursor.1 + dy).clamp(0, MAP_HEIGHT - 1));
+            }
+            None => {}
         }
-        // No monster was found
-        None => move_by(PLAYER, dx, dy, map, objects)
-    }
-}
+    };
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>) {
-    // choose random number of monsters
-    //
-    let num_monsters = rand::thread_rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
+    // erase the targeting help
+    tcod.root.set_default_background(colors::BLACK);
+    tcod.root.rect(0, MAP_HEIGHT, tcod.root.width(), 1, true, BackgroundFlag::Set);
+    target
+}
 
-    for _ in 0..num_monsters {
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+/// Move a cursor over the map with the movement keys, describing the tile under it and what
+/// is on it below the map, until Escape or the look key is pressed
+fn look(tcod: &mut Tcod, game: &Game) {
+    let mut cursor = game.objects[PLAYER].pos();
+    loop {
+        render_all(tcod, game, false);
+        draw_look(&mut tcod.root, game, cursor);
+        tcod.root.flush();
+        for object in &game.objects {
+            object.clear(&mut tcod.con);
+        }
 
-        if !is_blocked(x, y, map, objects) {
-            let mut monster = if rand::random::<f32>() < 0.8 {
-                let mut orc = Object::new(x, y, 'o', "orc", colors::GREEN, true);
-                orc.fighter = Some(Fighter { max_hp: 10, hp: 10, defense: 0, power: 3, on_death: DeathCallback::Monster });
-                orc.ai = Some(Ai);
-                orc
-            } else {
-                let mut troll = Object::new(x, y, 'T', "Troll", colors::DARKER_GREEN, true);
-                troll.fighter = Some(Fighter { max_hp: 16, hp: 16, defense: 1, power: 4, on_death: DeathCallback::Monster });
-                troll.ai = Some(Ai);
-                troll
-            };
-            monster.alive = true;
-            objects.push(monster);
+        let key = tcod.root.wait_for_keypress(true);
+        if tcod.root.window_closed() {
+            break;
         }
+        let (dx, dy) = match direction(&tcod.keymap, key) {
+            Some(offset) => offset,
+            None if key.code == input::KeyCode::Escape || command(&tcod.keymap, key) == Some(Command::Look) => break,
+            None => (0, 0),
+        };
+        cursor = ((cursor.0 + dx).clamp(0, MAP_WIDTH - 1), (cursor.1 + dy).clamp(0, MAP_HEIGHT - 1));
     }
 
-    // Choose randon number of items
-    let num_items = rand::thread_rng().gen_range(0, MAX_ROOM_ITEMS);
+    // erase the description
+    tcod.root.set_default_background(colors::BLACK);
+    tcod.root.rect(0, MAP_HEIGHT, tcod.root.width(), panel_y(&tcod.root) - MAP_HEIGHT, true, BackgroundFlag::Set);
+}
 
-    for _ in 0..num_items {
-        // Choose a random spot for the items
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+/// Draw the look cursor on the root console, and the description of the tile under it below the map
+fn draw_look(root: &mut Root, game: &Game, cursor: (i32, i32)) {
+    root.set_char_background(cursor.0, cursor.1, colors::LIGHT_YELLOW, BackgroundFlag::Set);
+
+    root.set_default_background(colors::BLACK);
+    root.rect(0, MAP_HEIGHT, root.width(), panel_y(root) - MAP_HEIGHT, true, BackgroundFlag::Set);
+    root.set_default_foreground(colors::LIGHT_GREY);
+    root.print_ex(1, MAP_HEIGHT, BackgroundFlag::None, TextAlignment::Left,
+                  "Looking around. Movement keys to move, Escape to stop.");
+    root.set_default_foreground(colors::WHITE);
+    let lines = game.describe(cursor.0, cursor.1);
+    for (row, line) in lines.iter().enumerate().take((panel_y(root) - MAP_HEIGHT - 1) as usize) {
+        root.print_ex(1, MAP_HEIGHT + 1 + row as i32, BackgroundFlag::None, TextAlignment::Left, line);
+    }
+}
 
-        // only place if the tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            // Create a healing potion
-            let mut object = Object::new(x, y, '!', "healing potion", colors::VIOLET, false);
-            object.item = Some(Item::Heal);
-            objects.push(object);
+/// Like `target_tile`, but only a monster can be picked. Returns its object id.
+fn target_monster(tcod: &mut Tcod, game: &Game, range: Option<f32>) -> Option<usize> {
+    loop {
+        let (x, y) = target_tile(tcod, game, range, false)?;
+        let monster_id = game.objects.iter().position(|object| object.pos() == (x, y) && object.ai.is_some());
+        if monster_id.is_some() {
+            return monster_id;
         }
     }
 }
 
-fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
-    assert_ne!(first_index, second_index);
-    let split_at_index = cmp::max(first_index, second_index);
-    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
-
-    if first_index < second_index {
-        (&mut first_slice[first_index], &mut second_slice[0])
-    } else {
-        (&mut second_slice[0], &mut first_slice[second_index])
+/// Draw the targeting cursor, range and flight over the map on the root console, with a line
+/// of help below the map
+fn draw_targeting(root: &mut Root, game: &Game, cursor: (i32, i32), range: Option<f32>, valid: bool,
+                  flight: &[(i32, i32)]) {
+    // darken the tiles in view that are out of range
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if game.fov_map.is_in_fov(x, y) && !game::in_target_range((x, y), range, &game.objects, &game.fov_map) {
+                root.set_char_background(x, y, COLOR_OUT_OF_RANGE, BackgroundFlag::Multiply);
+            }
+        }
     }
+    for &(x, y) in flight {
+        root.set_char_background(x, y, COLOR_FLIGHT, BackgroundFlag::Set);
+    }
+    let cursor_color = if valid { colors::LIGHT_CYAN } else { colors::RED };
+    root.set_char_background(cursor.0, cursor.1, cursor_color, BackgroundFlag::Set);
+
+    let names = get_names_at(cursor.0, cursor.1, &game.objects, &game.fov_map);
+    let help = match range {
+        Some(range) => format!("Range {}. Enter or left-click to target, Escape or right-click to cancel. {}",
+                               range, names),
+        None => format!("Enter or left-click to target, Escape or right-click to cancel. {}", names),
+    };
+    root.set_default_background(colors::BLACK);
+    root.rect(0, MAP_HEIGHT, root.width(), 1, true, BackgroundFlag::Set);
+    root.set_default_foreground(colors::LIGHT_GREY);
+    root.print_ex(1, MAP_HEIGHT, BackgroundFlag::None, TextAlignment::Left, help);
 }
 
-fn ai_take_turn(monster_id: usize, map: &Map, objects: &mut [Object], fov_map: &FovMap, messages: &mut Messages) {
-    // a basic monster takes its turn. If you can see it, it can see you
-    let (monster_x, monster_y) = objects[monster_id].pos();
-    // TODO finish AI take turn
-    if fov_map.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // move towards player if far away
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, map, objects);
-        } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-            // Close enough to attack if the player is still alive
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-
-            monster.attack(player, messages);
+/// Use an item from the inventory, letting the player pick a target first if it needs one
+fn use_item(inventory_index: usize, tcod: &mut Tcod, game: &mut Game) {
+    let item = match game.inventory[inventory_index].item {
+        Some(item) => item,
+        None => {
+            game.step(Action::UseItem(inventory_index, None));
+            return;
+        }
+    };
+    let target = match item.targeting() {
+        Targeting::Nothing => Some(None),
+        Targeting::Tile => target_tile(tcod, game, item.range(), false).map(Some),
+        Targeting::Monster => target_monster(tcod, game, item.range())
+            .map(|monster_id| Some(game.objects[monster_id].pos())),
+    };
+    match target {
+        Some(target) => {
+            game.step(Action::UseItem(inventory_index, target));
         }
+        None => message(&mut game.messages, "Cancelled", game_colors::WHITE),
     }
 }
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
-    // vector from this object to the target, and distance
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
-    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+/// Show the whole message history on the screen until Escape is pressed. The arrow keys,
+/// PgUp/PgDn and Home/End scroll, `/` starts typing a search that only shows the matching
+/// messages.
+fn message_log(root: &mut Root, game: &Game) {
+    use tcod::input::KeyCode::*;
 
-    // Normalize it to length 1 then round and convert to integer
-    // so that the movement is restricted to a grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-    move_by(id, dx, dy, map, objects);
-}
+    // below the title and search lines
+    let rows = (root.height() - 3) as usize;
+    let mut query = String::new();
+    let mut searching = false;
+    // how many messages are scrolled back from the newest
+    let mut scroll = 0;
+
+    loop {
+        let lowered = query.to_lowercase();
+        let matching: Vec<_> = game.messages.iter()
+            .filter(|message| message.text.to_lowercase().contains(&lowered))
+            .collect();
+        let max_scroll = matching.len().saturating_sub(rows);
+        scroll = cmp::min(scroll, max_scroll);
+        let end = matching.len() - scroll;
+        let start = end.saturating_sub(rows);
+
+        root.set_default_background(colors::BLACK);
+        root.clear();
+        root.set_default_foreground(colors::WHITE);
+        root.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left,
+                      format!("Message log, {} of {} messages. Arrows, PgUp/PgDn, Home/End to scroll, \
+                               / to search, Escape to close", matching.len(), game.messages.len()));
+        root.set_default_foreground(colors::LIGHT_GREY);
+        let search = match (searching, query.is_empty()) {
+            (true, _) => format!("Search: {}_", query),
+            (false, false) => format!("Search: {}", query),
+            (false, true) => String::new(),
+        };
+        root.print_ex(1, 1, BackgroundFlag::None, TextAlignment::Left, search);
 
+        for (row, message) in matching[start..end].iter().enumerate() {
+            root.set_default_foreground(tcod_color(message.color));
+            root.print_ex(1, 3 + row as i32, BackgroundFlag::None, TextAlignment::Left,
+                          format!("{:>6}  {}", message.turn, message.display_text()));
+        }
+        root.flush();
 
-fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    // first test the map tile
-    if map[x as usize][y as usize].blocked {
-        return true;
+        let key = root.wait_for_keypress(true);
+        if root.window_closed() {
+            break;
+        }
+        if searching {
+            match key.code {
+                Enter => searching = false,
+                Escape => {
+                    query.clear();
+                    searching = false;
+                }
+                Backspace => {
+                    query.pop();
+                }
+                _ if !key.printable.is_control() && key.printable != '\0' => query.push(key.printable),
+                _ => {}
+            }
+            continue;
+        }
+        match key.code {
+            Up => scroll = cmp::min(scroll + 1, max_scroll),
+            Down => scroll = scroll.saturating_sub(1),
+            PageUp => scroll = cmp::min(scroll + rows, max_scroll),
+            PageDown => scroll = scroll.saturating_sub(rows),
+            Home => scroll = max_scroll,
+            End => scroll = 0,
+            Escape => break,
+            _ if key.printable == '/' => {
+                query.clear();
+                searching = true;
+            }
+            _ => {}
+        }
     }
-    //println!("x {}, y {} is blocked", x, y);
-
-    // now check for any blocking objects
-    let monster_block = objects.iter().any(|object| {
-        object.blocks && object.pos() == (x, y)
-    });
 
-    //println!("blocked by object");
-    monster_block
+    // make room for the map and panel again
+    root.set_default_background(colors::BLACK);
+    root.clear();
 }
 
-fn menu<T: AsRef<str>>(header: &str,
-                       options: &[T],
-                       width: i32,
-                       root: &mut Root) -> Option<usize> {
-    assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
-    // Calculate total height for the header (after auto-wrap) and one line per option
-    let header_height = root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
-    let height = options.len() as i32 + header_height;
-
-    let mut window = Offscreen::new(width, height);
-    window.set_default_foreground(colors::WHITE);
-    window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, header);
-
-    for (index, option_text) in options.iter().enumerate() {
-        let menu_letter = (b'a' + index as u8) as char;
-        let text = format!("({}) {}", menu_letter, option_text.as_ref());
-        window.print_ex(0, header_height + index as i32,
-                        BackgroundFlag::None, TextAlignment::Left, text);
-    }
-    // blit the contents of "window" to the root console
-    let x = SCREEN_WIDTH / 2 - width / 2;
-    let y = SCREEN_HEIGHT / 2 - height / 2;
-    tcod::console::blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
-
-    // Present the root console to the player and wait for a key-press
-    root.flush();
-    let key = root.wait_for_keypress(true);
-
-    // Convert the ASCII code to an index; if it corresponds to an optoion return it
-    if key.printable.is_alphabetic() {
-        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-        if index < options.len() {
-            Some(index)
-        } else {
-            None
-        }
-    } else {
-        None
+/// Let the player pick a stat to raise, for every level up the player has earned
+fn level_up(tcod: &mut Tcod, game: &mut Game) {
+    while game.can_level_up() && !tcod.root.window_closed() {
+        let player = &game.objects[PLAYER];
+        let fighter = match player.fighter {
+            Some(fighter) => fighter,
+            None => return,
+        };
+        let options = &[
+            format!("Constitution (+{} HP, from {})", LEVEL_UP_HP, fighter.base_max_hp),
+            format!("Strength (+{} attack, from {})", LEVEL_UP_POWER, fighter.base_power),
+            format!("Agility (+{} defense, from {})", LEVEL_UP_DEFENSE, fighter.base_defense),
+        ];
+        let choice = menu("Level up! Choose a stat to raise:\n", options, LEVEL_SCREEN_WIDTH, &mut tcod.root);
+        let stat = match choice {
+            Some(0) => Stat::Hp,
+            Some(1) => Stat::Power,
+            Some(2) => Stat::Defense,
+            // keep asking until a stat is chosen
+            _ => continue,
+        };
+        game.step(Action::LevelUp(stat));
     }
 }
 
-fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
-    // How a menu with each item of the invetory as an option
-    let options = if inventory.len() == 0 {
-        vec!["Inventory is empty".into()]
-    } else {
-        inventory.iter().map(|item| { item.name.clone() }).collect()
+/// The name a keymap file uses for the key pressed, with the modifiers held down
+fn key_press(key: Key) -> Option<KeyPress> {
+    use tcod::input::KeyCode::*;
+    let name = match key.code {
+        Escape => "escape",
+        Backspace => "backspace",
+        Tab => "tab",
+        Enter => "enter",
+        Spacebar => "space",
+        Insert => "insert",
+        Delete => "delete",
+        Up => "up",
+        Down => "down",
+        Left => "left",
+        Right => "right",
+        Home => "home",
+        End => "end",
+        PageUp => "pageup",
+        PageDown => "pagedown",
+        NumPad0 => "numpad0",
+        NumPad1 => "numpad1",
+        NumPad2 => "numpad2",
+        NumPad3 => "numpad3",
+        NumPad4 => "numpad4",
+        NumPad5 => "numpad5",
+        NumPad6 => "numpad6",
+        NumPad7 => "numpad7",
+        NumPad8 => "numpad8",
+        NumPad9 => "numpad9",
+        NumPadAdd => "numpadadd",
+        NumPadSubtract => "numpadsubtract",
+        NumPadMultiply => "numpadmultiply",
+        NumPadDivide => "numpaddivide",
+        NumPadDecimal => "numpaddecimal",
+        NumPadEnter => "numpadenter",
+        F1 => "f1",
+        F2 => "f2",
+        F3 => "f3",
+        F4 => "f4",
+        F5 => "f5",
+        F6 => "f6",
+        F7 => "f7",
+        F8 => "f8",
+        F9 => "f9",
+        F10 => "f10",
+        F11 => "f11",
+        F12 => "f12",
+        // the character typed, as it ends up with the keyboard layout and shift
+        _ if key.printable != '\0' && !key.printable.is_control() =>
+            return Some(KeyPress::new(key.printable.to_string(), key.ctrl, key.alt)),
+        _ => return None,
     };
+    Some(KeyPress::new(name, key.ctrl, key.alt))
+}
 
-    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+/// The command the keymap binds to a key, if any
+fn command(keymap: &Keymap, key: Key) -> Option<Command> {
+    key_press(key).and_then(|press| keymap.command(&press))
+}
 
-    // If an item was chosen, return it
-    if inventory.len() > 0 {
-        inventory_index
-    } else {
-        None
-    }
+/// The offset a movement key points in
+fn direction(keymap: &Keymap, key: Key) -> Option<(i32, i32)> {
+    command(keymap, key).and_then(Command::offset)
 }
 
-fn handle_keys(key: Key,
-               root: &mut Root,
-               objects: &mut Vec<Object>,
-               inventory: &mut Vec<Object>,
-               map: &Map,
-               messages: &mut Messages) -> PlayerAction {
-    use tcod::input::Key;
-    use tcod::input::KeyCode::*;
+fn handle_keys(key: Key, tcod: &mut Tcod, game: &mut Game) -> PlayerAction {
     use PlayerAction::*;
 
-    let player_alive = objects[PLAYER].alive;
+    let command = match command(&tcod.keymap, key) {
+        Some(command) => command,
+        None => return Continue,
+    };
+    let player_alive = game.objects[PLAYER].alive;
 
-    match (key, player_alive) {
+    match (command, player_alive) {
         // Toggle fullscreen
-        (Key { code: Enter, ctrl: true, .. }, true) => {
-            let fullscreen = root.is_fullscreen();
-            root.set_fullscreen(fullscreen);
-            DidntTakeTurn
+        (Command::Fullscreen, true) => {
+            let fullscreen = tcod.root.is_fullscreen();
+            tcod.root.set_fullscreen(!fullscreen);
         }
         // movement keys
-        (Key { code: Up, .. }, true) => {
-            player_move_or_attack(0, -1, map, objects, messages);
-            TookTurn
+        (command, true) if command.offset().is_some() => {
+            if let Some((dx, dy)) = command.offset() {
+                game.step(Action::Move(dx, dy));
+            }
         }
-        (Key { code: Down, .. }, true) => {
-            player_move_or_attack(0, 1, map, objects, messages);
-            TookTurn
+        (Command::Wait, true) => {
+            // rest a turn
+            game.step(Action::Wait);
         }
-        (Key { code: Left, .. }, true) => {
-            player_move_or_attack(-1, 0, map, objects, messages);
-            TookTurn
+        (Command::PickUp, true) => {
+            // pick up an item
+            game.step(Action::PickUp);
         }
-        (Key { code: Right, .. }, true) => {
-            player_move_or_attack(1, 0, map, objects, messages);
-            TookTurn
+        (Command::Descend, true) => {
+            // go down the stairs, if the player is on them
+            game.step(Action::Descend);
         }
-        (Key { printable: 'g', .. }, true) => {
-            // pick up an item
-            let item_id = objects.iter().position(|object| {
-                object.pos() == objects[PLAYER].pos() && object.item.is_some()
-            });
-            if let Some(item_id) = item_id {
-                pick_item_up(item_id, objects, inventory, messages);
+        (Command::Quit, _) => return Exit,
+        (Command::Inventory, true) => {
+            // Show the inventory
+            let choice = inventory_menu(game,
+                           "Press the key next to an item to use or wear it, or any other to cancel. \n",
+                           &mut tcod.root);
+            match choice {
+                Some(InventoryChoice::Carried(inventory_index)) => use_item(inventory_index, tcod, game),
+                Some(InventoryChoice::Worn(index)) => {
+                    game.step(Action::Unequip(index));
+                }
+                None => {}
             }
-            DidntTakeTurn
         }
-        (Key { code: Escape, .. }, _) => { Exit }
-        (Key { printable: 'i', .. }, true) => {
-            // Show the inventory
-            let inventory_index = inventory_menu(inventory,
-                           "Press the key next to an item to use it, or any other to cancel. \n",
-                           root);
-            if let Some(inventory_index) = inventory_index {
-                use_item(inventory_index, inventory, objects, messages);
+        (Command::Drop, true) => {
+            // Drop an item on the floor
+            let choice = inventory_menu(game,
+                           "Press the key next to an item to drop it, or any other to cancel.\n",
+                           &mut tcod.root);
+            match choice {
+                Some(InventoryChoice::Carried(inventory_index)) => {
+                    game.step(Action::Drop(inventory_index));
+                }
+                Some(InventoryChoice::Worn(_)) => {
+                    message(&mut game.messages, "Take it off first.", game_colors::WHITE);
+                }
+                None => {}
+            }
+        }
+        (Command::Throw, true) => {
+            // Throw an item at a tile
+            let choice = inventory_menu(game,
+                           "Press the key next to an item to throw it, or any other to cancel.\n",
+                           &mut tcod.root);
+            match choice {
+                Some(InventoryChoice::Carried(inventory_index)) => {
+                    match target_tile(tcod, game, Some(THROW_RANGE), true) {
+                        Some(target) => {
+                            game.step(Action::Throw(inventory_index, target));
+                        }
+                        None => message(&mut game.messages, "Cancelled", game_colors::WHITE),
+                    }
+                }
+                Some(InventoryChoice::Worn(_)) => {
+                    message(&mut game.messages, "Take it off first.", game_colors::WHITE);
+                }
+                None => {}
             }
-            DidntTakeTurn
+        }
+        (Command::Look, _) => {
+            // Look around the map
+            look(tcod, game);
+        }
+        (Command::MessageLog, _) => {
+            // Show the message history
+            message_log(&mut tcod.root, game);
+        }
+        (Command::Character, true) => {
+            // Show the character information
+            character_screen(game, &mut tcod.root);
         }
 
-        _ => DidntTakeTurn,
+        _ => {}
     }
+    Continue
 }
 
-/// Return a string with the names of all objects under the mouse
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
-
-    // Create a list with the names of all objects ath the mouses coordinates and in FOV
+/// Return a string with the names of all objects on a tile
+fn get_names_at(x: i32, y: i32, objects: &[Object], fov_map: &FovMap) -> String {
+    // Create a list with the names of all objects ath the coordinates and in FOV
     let names = objects.iter()
         .filter(|obj| { obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y) })
-        .map(|obj| obj.name.clone())
+        .map(|obj| obj.display_name())
         .collect::<Vec<_>>();
 
     names.join(", ")
 }
 
-fn render_all(root: &mut Root,
-              con: &mut Offscreen,
-              objects: &[Object],
-              map: &mut Map,
-              fov_map: &mut tcod::map::Map,
-              fov_recompute: bool,
-              mouse: Mouse,
-              panel: &mut Offscreen,
-              messages: &Messages) {
-    if fov_recompute {
-        // Recompute fov if needed
-        let player: &Object = &objects[PLAYER];
-
-        fov_map.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+/// The row the panel starts at, at the bottom of the screen
+fn panel_y(root: &Root) -> i32 {
+    root.height() - PANEL_HEIGHT
+}
 
+fn render_all(tcod: &mut Tcod, game: &Game, fov_recompute: bool) {
+    let Tcod { ref mut root, ref mut con, ref mut panel, mouse, .. } = *tcod;
+    let fov_map = &game.fov_map;
+    if fov_recompute {
         // go through all tiles, and set their background color
         for y in 0..MAP_HEIGHT {
             for x in 0..MAP_WIDTH {
                 let visible = fov_map.is_in_fov(x, y);
-                let wall = map[x as usize][y as usize].block_sight;
-                let color = match (visible, wall) {
+                let tile = &game.map[x as usize][y as usize];
+                let color = match (visible, tile.block_sight) {
                     // outside of field of view:
                     (false, true) => COLOR_DARK_WALL,
                     (false, false) => COLOR_DARK_GROUND,
@@ -696,27 +727,24 @@ fn render_all(root: &mut Root,
                     (true, false) => COLOR_LIGHT_GROUND,
                 };
 
-                let explored = &mut map[x as usize][y as usize].explored;
-                if visible {
-                    *explored = true;
-                }
-
-                if *explored {
+                if tile.explored {
                     con.set_char_background(x, y, color, BackgroundFlag::Set);
                 }
             }
         }
     }
-    let mut to_draw: Vec<_> =
-        objects.iter().filter(|o| fov_map.is_in_fov(o.x, o.y)).collect();
+    let mut to_draw: Vec<_> = game.objects.iter().filter(|o| {
+        fov_map.is_in_fov(o.x, o.y) ||
+            (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
+    }).collect();
     // Sort so that non-blocking objects come first
     to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
     // Draw the objects in the list
     for object in &to_draw {
-        object.draw(con);
+        object.draw(&mut *con);
     }
 
-    blit(con, (0, 0), (SCREEN_WIDTH, SCREEN_HEIGHT), root, (0, 0), 1.0, 1.0);
+    blit(con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), root, (0, 0), 1.0, 1.0);
 
     // Render the GUI
     // prepare to render the GUI panel
@@ -724,47 +752,51 @@ fn render_all(root: &mut Root,
     panel.clear();
 
     // Show the player stats
-    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
-    let max_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp);
+    let hp = game.objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    let max_hp = game.objects[PLAYER].max_hp();
     render_bar(panel, 1, 1, BAR_WIDTH,
                "HP",
                hp,
                max_hp,
                colors::LIGHT_RED, colors::DARKER_RED);
+    let xp = game.objects[PLAYER].fighter.map_or(0, |f| f.xp);
+    render_bar(panel, 1, 2, BAR_WIDTH,
+               "XP",
+               xp,
+               game.level_up_xp(),
+               colors::LIGHT_VIOLET, colors::DARKER_VIOLET);
+    panel.print_ex(1, 4, BackgroundFlag::None, TextAlignment::Left,
+                   format!("Character level: {}", game.objects[PLAYER].level));
+    panel.print_ex(1, 5, BackgroundFlag::None, TextAlignment::Left,
+                   format!("Dungeon level: {}", game.depth));
 
     // display names of objects under the mouse
     panel.set_default_foreground(colors::LIGHT_GREY);
     panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left,
-                   get_names_under_mouse(mouse, objects, fov_map));
+                   get_names_at(mouse.cx as i32, mouse.cy as i32, &game.objects, fov_map));
 
     // print the game messages one line at a time
+    let msg_width = panel.width() - MSG_X;
     let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in messages.iter().rev() {
-        let msg_height = panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    for message in game.messages.iter().rev() {
+        let msg = message.display_text();
+        let msg_height = panel.get_height_rect(MSG_X, y, msg_width, 0, &msg);
         y -= msg_height;
         if y < 0 {
             break;
         }
-        panel.set_default_foreground(color);
-        panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        panel.set_default_foreground(tcod_color(message.color));
+        panel.print_rect(MSG_X, y, msg_width, 0, msg);
     }
 
     // blit the contents of `panel` to the root console
     blit(panel, (0, 0),
-         (SCREEN_WIDTH, PANEL_HEIGHT),
-         root, (0, PANEL_Y),
+         (panel.width(), PANEL_HEIGHT),
+         root, (0, panel_y(root)),
          1.0, 1.0)
 }
 
-fn message<T: Into<String>>(messages: &mut Messages, message: T, color: Color) {
-    // If the buffer is full, remove the first message to make room for the new one
-    if messages.len() == MSG_HEIGHT {
-        messages.remove(0);
-    }
-
-    messages.push((message.into(), color));
-}
-
+#[allow(clippy::too_many_arguments)]
 fn render_bar(panel: &mut Offscreen,
               x: i32,
               y: i32,
@@ -791,92 +823,233 @@ fn render_bar(panel: &mut Offscreen,
     // finally, some centered text with values
     panel.set_default_foreground(colors::WHITE);
     panel.print_ex(x + total_width / 2, y, BackgroundFlag::None,
-                   TextAlignment::Center, &format!("{}: {}/{}", name, value, maximum));
+                   TextAlignment::Center, format!("{}: {}/{}", name, value, maximum));
 }
 
+/// Check that the command line is made of known flags, each followed by its value
+fn check_args() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        let flag = &pair[0];
+        if !GAME_FLAGS.contains(&flag.as_str()) && !SETTING_FLAGS.contains(&flag.as_str()) {
+            return Err(format!("Unknown flag: {}", flag));
+        }
+        if pair.len() < 2 {
+            return Err(format!("{} requires a value", flag));
+        }
+    }
+    Ok(())
+}
 
-fn main() {
-    let mut root = Root::initializer()
-        //.font("/Users/timdejager/.cargo/registry/src/github.com-1ecc6299db9ec823/tcod-0.12.1/fonts/consolas12x12_gs_tc.png", FontLayout::Tcod)
-        .font("/home/tim/.cargo/registry/src/github.com-1ecc6299db9ec823/tcod-0.12.1/fonts/consolas12x12_gs_tc.png", FontLayout::Tcod)
-
-        .font_type(FontType::Greyscale)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("Rust/libtcod tutorial")
-        .init();
+/// The value following the given flag on the command line, if the flag was passed
+fn arg_value(flag: &str) -> Result<Option<String>, String> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) => match args.get(index + 1) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(format!("{} requires a value", flag)),
+        },
+        None => Ok(None),
+    }
+}
 
-    let mut con = Offscreen::new(MAP_WIDTH, MAP_HEIGHT);
-    let mut panel = Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT);
+/// Read the seed from the `--seed <number>` flag, or pick a fresh one
+fn seed_from_args() -> Result<u64, String> {
+    match arg_value("--seed")? {
+        Some(value) => value.parse().map_err(|_| format!("Invalid seed: {}", value)),
+        None => Ok(game::random_seed()),
+    }
+}
 
-    tcod::system::set_fps(LIMIT_FPS);
+/// Read the map generator from the `--generator <rooms|bsp|caves>` flag
+fn generator_from_args() -> Result<MapGenerator, String> {
+    match arg_value("--generator")? {
+        Some(value) => value.parse(),
+        None => Ok(MapGenerator::Rooms),
+    }
+}
 
-    let mut objects: Vec<Object> = vec![];
-    let mut map = make_map(&mut objects);
-    // Create the list of game messages and their color. starts empty
-    let mut messages = vec![];
+fn new_game(data: &GameData, seed: u64, generator: MapGenerator) -> Game {
+    // The game state: map, objects, inventory and messages
+    println!("Starting game with seed {} and the {:?} map generator", seed, generator);
+    let mut game = Game::with_data(seed, generator, data.clone());
+    message(&mut game.messages, format!("Dungeon seed: {}", seed), game_colors::WHITE);
+    game
+}
 
-    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+/// Show a message in a box, until a key is pressed
+fn msgbox(text: &str, width: i32, root: &mut Root) {
+    let options: &[&str] = &[];
+    menu(text, options, width, root);
+}
 
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            fov_map.set(x, y,
-                        !map[x as usize][y as usize].block_sight,
-                        !map[x as usize][y as usize].blocked);
+/// Let the player start a new game from the seed or continue the saved one, returns None to quit
+fn main_menu(root: &mut Root, data: &GameData, seed: u64, generator: MapGenerator) -> Option<Game> {
+    loop {
+        root.set_default_background(colors::BLACK);
+        root.clear();
+        root.set_default_foreground(colors::LIGHT_YELLOW);
+        root.print_ex(root.width() / 2, root.height() / 2 - 4,
+                      BackgroundFlag::None, TextAlignment::Center,
+                      "ST HORROR DUNGEON");
+
+        let choices = &["Play a new game", "Continue last game", "Quit"];
+        match menu("", choices, MAIN_MENU_WIDTH, root) {
+            Some(0) => return Some(new_game(data, seed, generator)),
+            Some(1) => match load_game(Path::new(SAVE_FILE)) {
+                Ok(mut game) => {
+                    // the data is not saved, the levels still to come use the current data
+                    game.data = data.clone();
+                    return Some(game);
+                }
+                Err(err) => msgbox(&format!("\n{}\n", err), MAIN_MENU_WIDTH, root),
+            },
+            Some(2) => return None,
+            _ => {}
+        }
+        if root.window_closed() {
+            return None;
         }
     }
+}
 
-    // Print welcome message
-    message(&mut messages,
-            "Welcome stranger! Prepare to perish in the ST horror dungeon",
-            colors::RED);
-
-    let mut mouse = Default::default();
+fn play_game(tcod: &mut Tcod, mut game: Game) {
     let mut key = Default::default();
 
     let mut previous_player_position = (-1, -1);
-    let mut inventory: Vec<Object> = vec![];
-
-    while !root.window_closed() {
-        con.set_default_foreground(colors::WHITE);
+    let mut previous_depth = game.depth;
+
+    while !tcod.root.window_closed() {
+        tcod.con.set_default_foreground(colors::WHITE);
+        if previous_depth != game.depth {
+            // A new level, forget everything drawn for the previous one
+            tcod.con.clear();
+            previous_player_position = (-1, -1);
+            previous_depth = game.depth;
+        }
 
         match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => mouse = m,
+            Some((_, Event::Mouse(m))) => tcod.mouse = m,
             Some((_, Event::Key(k))) => key = k,
             _ => key = Default::default()
         };
 
-        let fov_recompute = previous_player_position != objects[PLAYER].pos();
-        render_all(&mut root, &mut con,
-                   &objects, &mut map,
-                   &mut fov_map,
-                   fov_recompute,
-                   mouse,
-                   &mut panel, &messages);
-        root.flush();
+        let fov_recompute = previous_player_position != game.objects[PLAYER].pos();
+        render_all(tcod, &game, fov_recompute);
+        tcod.root.flush();
 
-        for object in &objects {
-            object.clear(&mut con);
+        for object in &game.objects {
+            object.clear(&mut tcod.con);
         }
 
         // Check for exit and handle keys
-        {
-            let player = &objects[PLAYER];
-            previous_player_position = (player.x, player.y);
-        }
-        let player_action = handle_keys(key, &mut root, &mut objects, &mut inventory, &map, &mut messages);
+        previous_player_position = game.objects[PLAYER].pos();
+        let player_action = handle_keys(key, tcod, &mut game);
+        level_up(tcod, &mut game);
 
         if player_action == PlayerAction::Exit {
             break;
         }
+    }
 
-        if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, &map, &mut objects, &fov_map, &mut messages);
-                }
-            }
+    // Save on exit, a dead character cannot be continued
+    if game.objects[PLAYER].alive {
+        if let Err(err) = save_game(&game, Path::new(SAVE_FILE)) {
+            eprintln!("{}", err);
+        }
+    } else if Path::new(SAVE_FILE).exists() {
+        if let Err(err) = fs::remove_file(SAVE_FILE) {
+            eprintln!("Could not remove the save file: {}", err);
         }
     }
 }
 
+/// Load the key bindings from the keymap file, or use the default ones when there is none
+fn load_keymap() -> Result<Keymap, KeymapError> {
+    let path = Path::new(KEYMAP_FILE);
+    if path.exists() {
+        Keymap::load(path)
+    } else {
+        Ok(Keymap::default())
+    }
+}
+
+/// Load the settings from the settings file, if there is one, and the command line
+/// flags, and check that the game fits on the screen
+fn load_settings() -> Result<Settings, SettingsError> {
+    let path = Path::new(SETTINGS_FILE);
+    let mut settings = if path.exists() { Settings::load(path)? } else { Settings::default() };
+    for flag in SETTING_FLAGS {
+        let setting = flag[2..].replace('-', "_");
+        let value = arg_value(flag)
+            .map_err(|_| SettingsError::Invalid { setting: setting.clone(), reason: "no value was given".into() })?;
+        if let Some(value) = value {
+            settings.set(&setting, &value)?;
+        }
+    }
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn font_layout(layout: settings::FontLayout) -> FontLayout {
+    match layout {
+        settings::FontLayout::Tcod => FontLayout::Tcod,
+        settings::FontLayout::AsciiInCol => FontLayout::AsciiInCol,
+        settings::FontLayout::AsciiInRow => FontLayout::AsciiInRow,
+    }
+}
+
+fn font_type(font_type: settings::FontType) -> FontType {
+    match font_type {
+        settings::FontType::Default => FontType::Default,
+        settings::FontType::Greyscale => FontType::Greyscale,
+    }
+}
+
+/// Report an error that keeps the game from starting
+fn exit_with_error(err: impl std::fmt::Display) -> ! {
+    eprintln!("{}", err);
+    std::process::exit(1);
+}
 
+fn main() {
+    check_args().unwrap_or_else(|err| exit_with_error(err));
+    let data = GameData::load(Path::new(DATA_DIR)).unwrap_or_else(|err| exit_with_error(err));
+    if let Some(depth) = arg_value("--spawn-table").unwrap_or_else(|err| exit_with_error(err)) {
+        // print what the rooms at the depth get, for balancing, instead of playing
+        let depth = match depth.parse() {
+            Ok(number) if number >= 1 => number,
+            _ => exit_with_error(format!("Invalid depth: {}, the levels start at 1", depth)),
+        };
+        print!("{}", data.distribution(depth));
+        return;
+    }
+    let seed = seed_from_args().unwrap_or_else(|err| exit_with_error(err));
+    let generator = generator_from_args().unwrap_or_else(|err| exit_with_error(err));
+    let settings = load_settings().unwrap_or_else(|err| exit_with_error(err));
+    let font = settings.find_font().unwrap_or_else(|err| exit_with_error(err));
+    let keymap = load_keymap().unwrap_or_else(|err| exit_with_error(format!("{}: {}", KEYMAP_FILE, err)));
+
+    let root = Root::initializer()
+        .font(font, font_layout(settings.font_layout))
+        .font_type(font_type(settings.font_type))
+        .size(settings.screen_width, settings.screen_height)
+        .title("Rust/libtcod tutorial")
+        .init();
+
+    tcod::system::set_fps(settings.fps);
+
+    let mut tcod = Tcod {
+        root,
+        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        panel: Offscreen::new(settings.screen_width, PANEL_HEIGHT),
+        mouse: Default::default(),
+        keymap,
+    };
+
+    if let Some(mut game) = main_menu(&mut tcod.root, &data, seed, generator) {
+        // a loaded game keeps as many messages as a new one, whatever it was saved with
+        game.messages.set_capacity(settings.message_history);
+        play_game(&mut tcod, game);
+    }
+}
@@ -0,0 +1,551 @@
+use rand::Rng;
+use std::cmp;
+use std::str::FromStr;
+
+use data::GameData;
+use game::GameRng;
+use object::{Ai, Object, PLAYER};
+
+// Map properties
+pub const MAP_WIDTH: i32 = 80;
+pub const MAP_HEIGHT: i32 = 43;
+
+// Room properties
+const ROOM_MAX_SIZE: i32 = 10;
+const ROOM_MIN_SIZE: i32 = 10;
+const MAX_ROOMS: i32 = 10;
+
+// Binary space partitioning properties
+const BSP_DEPTH: i32 = 4;
+const BSP_MIN_LEAF_SIZE: i32 = 8;
+const BSP_MIN_ROOM_SIZE: i32 = 4;
+
+// Cave properties
+const CAVE_WALL_CHANCE: f32 = 0.45;
+const CAVE_SMOOTHING_STEPS: i32 = 5;
+const CAVE_SPAWN_AREA_SIZE: i32 = 16;
+
+// Monster stuff
+const WANDERING_CHANCE: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        let center_x = (self.x1 + self.x2) / 2;
+        let center_y = (self.y1 + self.y2) / 2;
+        (center_x, center_y)
+    }
+
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        // returns true if this rectangle intersects wit another one
+        (self.x1 <= other.x2) && (self.x2 >= other.x1) &&
+            (self.y1 <= other.y2) && (self.y2 >= other.y1)
+    }
+}
+
+fn create_room(room: Rect, map: &mut Map) {
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    let y_min = cmp::min(y1, y2);
+    let y_max = cmp::max(y1, y2);
+    for y in y_min..y_max + 1 {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Tile {
+    pub blocked: bool,
+    pub block_sight: bool,
+    pub explored: bool,
+}
+
+pub type Map = Vec<Vec<Tile>>;
+
+impl Tile {
+    pub fn empty() -> Self {
+        Tile { blocked: false, explored: false, block_sight: false }
+    }
+
+    pub fn wall() -> Self {
+        Tile { blocked: true, explored: false, block_sight: true }
+    }
+}
+
+/// The algorithm used to lay out the rooms of a level
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MapGenerator {
+    /// Rooms at random positions, dropped when they overlap an earlier room
+    Rooms,
+    /// Binary space partitioning: the map is split recursively and every part gets a room
+    Bsp,
+    /// Organic caverns grown with a cellular automaton
+    Caves,
+}
+
+impl FromStr for MapGenerator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "rooms" => Ok(MapGenerator::Rooms),
+            "bsp" => Ok(MapGenerator::Bsp),
+            "caves" => Ok(MapGenerator::Caves),
+            _ => Err(format!("Unknown map generator: {}, expected rooms, bsp or caves", name)),
+        }
+    }
+}
+
+/// Where things go on a freshly dug level
+struct Layout {
+    start: (i32, i32),
+    stairs: (i32, i32),
+    /// Areas to fill with monsters and items
+    spawn_areas: Vec<Rect>,
+}
+
+impl Layout {
+    /// Start in the first room, put the stairs in the last one and fill all of them
+    fn from_rooms(rooms: Vec<Rect>) -> Self {
+        Layout {
+            start: rooms[0].center(),
+            stairs: rooms[rooms.len() - 1].center(),
+            spawn_areas: rooms,
+        }
+    }
+}
+
+/// Build a new level around the player, which must be the first object.
+/// All other objects are removed, the player is moved to the start of the new level.
+/// Levels that are not fully connected are repaired, or generated again when that fails.
+/// The monsters come from the game data, as they turn up at the depth of the level.
+pub fn make_map(objects: &mut Vec<Object>, rng: &mut GameRng, generator: MapGenerator, data: &GameData,
+                depth: u32) -> Map {
+    loop {
+        let mut map = dig_level(objects, rng, generator, data, depth);
+        if validate_map(&map, objects).is_connected() {
+            return map;
+        }
+        let start = objects[PLAYER].pos();
+        repair_map(&mut map, start);
+        if validate_map(&map, objects).is_connected() {
+            return map;
+        }
+    }
+}
+
+fn dig_level(objects: &mut Vec<Object>, rng: &mut GameRng, generator: MapGenerator, data: &GameData,
+             depth: u32) -> Map {
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+
+    // Only the player is carried over from the previous level
+    objects.truncate(PLAYER + 1);
+
+    let layout = match generator {
+        MapGenerator::Rooms => Layout::from_rooms(random_rooms(&mut map, rng)),
+        MapGenerator::Bsp => Layout::from_rooms(bsp_rooms(&mut map, rng)),
+        MapGenerator::Caves => caves(&mut map, rng),
+    };
+
+    let (start_x, start_y) = layout.start;
+    objects[PLAYER].set_pos(start_x, start_y);
+
+    for area in &layout.spawn_areas {
+        // Add some content to the this room, such as monsters
+        place_objects(*area, &map, objects, data, depth, rng);
+    }
+
+    // Create the stairs down
+    let (stairs_x, stairs_y) = layout.stairs;
+    objects.push(Object::stairs(stairs_x, stairs_y));
+
+    map
+}
+
+/// Dig rooms at random positions, connecting every room to the previous one.
+/// Returns the rooms in the order they were dug.
+fn random_rooms(map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+    let mut rooms: Vec<Rect> = vec![];
+    for _ in 0..MAX_ROOMS {
+
+        // random width and height
+        let w = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        // random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, MAP_WIDTH - w);
+        let y = rng.gen_range(0, MAP_HEIGHT - h);
+
+        let new_room = Rect::new(x, y, w, h);
+
+        let failed = rooms.iter().any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            create_room(new_room, map);
+
+            // all rooms after the first:
+            // connect it to the previous room with a tunnel
+            if let Some(prev_room) = rooms.last() {
+                connect_rooms(*prev_room, new_room, map, rng);
+            }
+            rooms.push(new_room);
+        }
+    }
+    rooms
+}
+
+/// Dig one room in every leaf of a binary space partition of the map, connecting
+/// the two halves of every split. Returns the rooms from the top-left leaf onwards.
+fn bsp_rooms(map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+    let mut rooms = vec![];
+    let area = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+    bsp_split(area, BSP_DEPTH, map, rng, &mut rooms);
+    rooms
+}
+
+/// Split the area in two and recurse, or dig a room when it is a leaf.
+/// Returns one of the rooms in the area, to connect it to its sibling.
+fn bsp_split(area: Rect, depth: i32, map: &mut Map, rng: &mut GameRng, rooms: &mut Vec<Rect>) -> Rect {
+    let width = area.x2 - area.x1;
+    let height = area.y2 - area.y1;
+    let can_split_x = width >= 2 * BSP_MIN_LEAF_SIZE;
+    let can_split_y = height >= 2 * BSP_MIN_LEAF_SIZE;
+
+    if depth == 0 || !(can_split_x || can_split_y) {
+        // a leaf: dig a room of random size somewhere inside it
+        let w = rng.gen_range(BSP_MIN_ROOM_SIZE, width + 1);
+        let h = rng.gen_range(BSP_MIN_ROOM_SIZE, height + 1);
+        let x = rng.gen_range(area.x1, area.x2 - w + 1);
+        let y = rng.gen_range(area.y1, area.y2 - h + 1);
+        let room = Rect::new(x, y, w, h);
+        create_room(room, map);
+        rooms.push(room);
+        return room;
+    }
+
+    // cut across the longest side, the two halves share the wall on the cut
+    let cut_x = if can_split_x && can_split_y { width > height } else { can_split_x };
+    let (first, second) = if cut_x {
+        let x = rng.gen_range(area.x1 + BSP_MIN_LEAF_SIZE, area.x2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { x2: x, ..area }, Rect { x1: x, ..area })
+    } else {
+        let y = rng.gen_range(area.y1 + BSP_MIN_LEAF_SIZE, area.y2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { y2: y, ..area }, Rect { y1: y, ..area })
+    };
+
+    let first_room = bsp_split(first, depth - 1, map, rng, rooms);
+    let second_room = bsp_split(second, depth - 1, map, rng, rooms);
+    connect_rooms(first_room, second_room, map, rng);
+
+    if rng.gen() { first_room } else { second_room }
+}
+
+/// Connect the centers of two rooms with an L-shaped tunnel
+fn connect_rooms(prev_room: Rect, new_room: Rect, map: &mut Map, rng: &mut GameRng) {
+    let (prev_x, prev_y) = prev_room.center();
+    let (new_x, new_y) = new_room.center();
+
+    // toss a coin (random bool value -- either true or false)
+    if rng.gen() {
+        // first move horizontally, then vertically
+        create_h_tunnel(prev_x, new_x, prev_y, map);
+        create_v_tunnel(prev_y, new_y, new_x, map);
+    } else {
+        // first move vertically, then horizontally
+        create_v_tunnel(prev_y, new_y, prev_x, map);
+        create_h_tunnel(prev_x, new_x, new_y, map);
+    }
+}
+
+/// Grow caverns with a cellular automaton. Only the largest cavern is kept, so every
+/// floor tile can be reached from the start, the stairs go on the floor furthest away.
+fn caves(map: &mut Map, rng: &mut GameRng) -> Layout {
+    // scatter walls over the map, keeping the border solid
+    for x in 1..(MAP_WIDTH - 1) {
+        for y in 1..(MAP_HEIGHT - 1) {
+            if rng.gen::<f32>() >= CAVE_WALL_CHANCE {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+        }
+    }
+
+    // smooth: a tile becomes a wall when most of the tiles around it are walls
+    for _ in 0..CAVE_SMOOTHING_STEPS {
+        let previous = map.clone();
+        for x in 1..(MAP_WIDTH - 1) {
+            for y in 1..(MAP_HEIGHT - 1) {
+                let walls = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (dx, dy)))
+                    .filter(|&(dx, dy)| previous[(x + dx) as usize][(y + dy) as usize].blocked)
+                    .count();
+                map[x as usize][y as usize] = if walls >= 5 { Tile::wall() } else { Tile::empty() };
+            }
+        }
+    }
+
+    // find the largest cavern, and fill in all the others
+    let mut largest: Vec<(i32, i32)> = vec![];
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if map[x as usize][y as usize].blocked || seen[x as usize][y as usize] {
+                continue;
+            }
+            let cavern: Vec<(i32, i32)> = reachable_tiles(map, (x, y)).into_iter().map(|(pos, _)| pos).collect();
+            for &(cx, cy) in &cavern {
+                seen[cx as usize][cy as usize] = true;
+            }
+            if cavern.len() > largest.len() {
+                largest = cavern;
+            }
+        }
+    }
+    for column in map.iter_mut() {
+        for tile in column.iter_mut() {
+            *tile = Tile::wall();
+        }
+    }
+    for &(x, y) in &largest {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+
+    let start = largest[rng.gen_range(0, largest.len())];
+    let stairs = reachable_tiles(map, start).into_iter()
+        .max_by_key(|&(_, distance)| distance)
+        .map_or(start, |(pos, _)| pos);
+
+    // cut the map into squares to spread the monsters and items, place_objects skips walls
+    let mut spawn_areas = vec![];
+    for x in (0..(MAP_WIDTH - 1)).step_by(CAVE_SPAWN_AREA_SIZE as usize) {
+        for y in (0..(MAP_HEIGHT - 1)).step_by(CAVE_SPAWN_AREA_SIZE as usize) {
+            let w = cmp::min(CAVE_SPAWN_AREA_SIZE, MAP_WIDTH - 1 - x);
+            let h = cmp::min(CAVE_SPAWN_AREA_SIZE, MAP_HEIGHT - 1 - y);
+            spawn_areas.push(Rect::new(x, y, w, h));
+        }
+    }
+
+    Layout { start, stairs, spawn_areas }
+}
+
+/// All floor tiles that can be walked to from the start, with their distance in steps.
+/// Walking is horizontal and vertical only, objects are ignored.
+pub fn reachable_tiles(map: &Map, start: (i32, i32)) -> Vec<((i32, i32), i32)> {
+    let mut visited = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut reached = vec![(start, 0)];
+    visited[start.0 as usize][start.1 as usize] = true;
+
+    // breadth first, so the first time a tile is reached is along a shortest path
+    let mut next = 0;
+    while next < reached.len() {
+        let ((x, y), distance) = reached[next];
+        next += 1;
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= MAP_WIDTH || ny >= MAP_HEIGHT {
+                continue;
+            }
+            if map[nx as usize][ny as usize].blocked || visited[nx as usize][ny as usize] {
+                continue;
+            }
+            visited[nx as usize][ny as usize] = true;
+            reached.push(((nx, ny), distance + 1));
+        }
+    }
+    reached
+}
+
+/// What a flood fill from the player start could not reach
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MapReport {
+    pub unreachable_tiles: Vec<(i32, i32)>,
+    /// Indices into the objects
+    pub unreachable_objects: Vec<usize>,
+}
+
+impl MapReport {
+    pub fn is_connected(&self) -> bool {
+        self.unreachable_tiles.is_empty() && self.unreachable_objects.is_empty()
+    }
+}
+
+/// Flood fill from the player, which must be the first object, and report every floor
+/// tile and object that cannot be walked to
+pub fn validate_map(map: &Map, objects: &[Object]) -> MapReport {
+    let mut reachable = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for ((x, y), _) in reachable_tiles(map, objects[PLAYER].pos()) {
+        reachable[x as usize][y as usize] = true;
+    }
+
+    let mut report = MapReport::default();
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if !map[x as usize][y as usize].blocked && !reachable[x as usize][y as usize] {
+                report.unreachable_tiles.push((x, y));
+            }
+        }
+    }
+    report.unreachable_objects = objects.iter().enumerate()
+        .filter(|&(_, object)| !reachable[object.x as usize][object.y as usize])
+        .map(|(id, _)| id)
+        .collect();
+    report
+}
+
+/// Connect every pocket of floor that cannot be reached from the start with a tunnel
+fn repair_map(map: &mut Map, start: (i32, i32)) {
+    loop {
+        let mut reachable = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for ((x, y), _) in reachable_tiles(map, start) {
+            reachable[x as usize][y as usize] = true;
+        }
+        let pocket = (0..MAP_WIDTH)
+            .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+            .find(|&(x, y)| !map[x as usize][y as usize].blocked && !reachable[x as usize][y as usize]);
+        match pocket {
+            Some((x, y)) => {
+                create_h_tunnel(x, start.0, y, map);
+                create_v_tunnel(y, start.1, start.0, map);
+            }
+            None => return,
+        }
+    }
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, data: &GameData, depth: u32,
+                 rng: &mut GameRng) {
+    // choose random number of monsters, deeper rooms can get more
+    let num_monsters = rng.gen_range(0, data.spawns.max_monsters.at(depth) + 1);
+
+    for _ in 0..num_monsters {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        if !is_blocked(x, y, map, objects) {
+            let mut monster = match data.choose_monster(depth, rng) {
+                Some(template) => template.create(x, y),
+                None => continue,
+            };
+            // some monsters are already roaming around
+            if rng.gen::<f32>() < WANDERING_CHANCE {
+                monster.ai = Some(Ai::Wandering);
+            }
+            objects.push(monster);
+        }
+    }
+
+    // Choose randon number of items
+    let num_items = rng.gen_range(0, data.spawns.max_items.at(depth) + 1);
+
+    for _ in 0..num_items {
+        // Choose a random spot for the items
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        // only place if the tile is not blocked
+        if !is_blocked(x, y, map, objects) {
+            if let Some(template) = data.choose_item(depth, rng) {
+                objects.push(template.create(x, y));
+            }
+        }
+    }
+}
+
+pub fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    // there is nothing to stand on outside the map
+    if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT {
+        return true;
+    }
+    // first test the map tile
+    if map[x as usize][y as usize].blocked {
+        return true;
+    }
+
+    // now check for any blocking objects
+    objects.iter().any(|object| {
+        object.blocks && object.pos() == (x, y)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::seeded_rng;
+
+    const SEEDS: u64 = 300;
+
+    fn check_levels(generator: MapGenerator) {
+        let data = GameData::default();
+        for seed in 0..SEEDS {
+            let mut rng = seeded_rng(seed);
+            let mut objects = vec![Object::player()];
+            let depth = 1 + (seed % 6) as u32;
+            let map = make_map(&mut objects, &mut rng, generator, &data, depth);
+
+            let report = validate_map(&map, &objects);
+            assert!(report.is_connected(), "{:?} seed {}: {:?}", generator, seed, report);
+            assert!(objects.iter().all(|object| !map[object.x as usize][object.y as usize].blocked),
+                    "{:?} seed {}: an object is inside a wall", generator, seed);
+            assert_eq!(objects.iter().filter(|object| object.stairs).count(), 1);
+        }
+    }
+
+    #[test]
+    fn rooms_levels_are_connected() {
+        check_levels(MapGenerator::Rooms);
+    }
+
+    #[test]
+    fn bsp_levels_are_connected() {
+        check_levels(MapGenerator::Bsp);
+    }
+
+    #[test]
+    fn caves_levels_are_connected() {
+        check_levels(MapGenerator::Caves);
+    }
+
+    #[test]
+    fn a_walled_off_room_is_reported_and_repaired() {
+        let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        create_room(Rect::new(1, 1, 10, 10), &mut map);
+        create_room(Rect::new(40, 20, 10, 10), &mut map);
+        let mut player = Object::player();
+        player.set_pos(5, 5);
+        let objects = vec![player, Object::stairs(45, 25)];
+
+        let report = validate_map(&map, &objects);
+        assert!(!report.is_connected());
+        assert!(report.unreachable_tiles.contains(&(45, 25)));
+        assert_eq!(report.unreachable_objects, vec![1]);
+
+        repair_map(&mut map, (5, 5));
+        assert!(validate_map(&map, &objects).is_connected());
+    }
+
+    #[test]
+    fn generators_are_parsed_by_name() {
+        assert_eq!("caves".parse(), Ok(MapGenerator::Caves));
+        assert!("maze".parse::<MapGenerator>().is_err());
+    }
+}
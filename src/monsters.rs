@@ -0,0 +1,57 @@
+//! Monster templates, the kinds of monsters a level can be populated with.
+
+use colors::Color;
+use object::{Ai, DeathCallback, Fighter, Object, Tactics};
+use spawn::Spawn;
+
+/// A kind of monster, as described in the monster data file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonsterTemplate {
+    pub name: String,
+    pub glyph: char,
+    pub color: Color,
+    pub hp: i32,
+    pub defense: i32,
+    pub power: i32,
+    /// Experience the player gains for killing it
+    pub xp: i32,
+    /// How it closes in on the player
+    pub ai: Tactics,
+    /// Where and how often it turns up
+    pub spawn: Spawn,
+}
+
+impl MonsterTemplate {
+    /// Check that it has a name, can be killed and has no negative stats, and where it
+    /// turns up. Returns the reason the template is invalid
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("the name is empty".into());
+        }
+        if self.hp <= 0 {
+            return Err(format!("hp must be positive, not {}", self.hp));
+        }
+        if self.defense < 0 || self.power < 0 || self.xp < 0 {
+            return Err("defense, power and xp can not be negative".into());
+        }
+        self.spawn.validate()
+    }
+
+    /// A fresh, sleeping monster of this kind
+    pub fn create(&self, x: i32, y: i32) -> Object {
+        let mut monster = Object::new(x, y, self.glyph, &self.name, self.color, true);
+        monster.fighter = Some(Fighter {
+            base_max_hp: self.hp,
+            hp: self.hp,
+            base_defense: self.defense,
+            base_power: self.power,
+            xp: self.xp,
+            on_death: DeathCallback::Monster,
+        });
+        monster.ai = Some(Ai::Asleep);
+        monster.tactics = self.ai;
+        monster.alive = true;
+        monster
+    }
+}
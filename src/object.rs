@@ -0,0 +1,519 @@
+use std::cmp;
+
+use ai;
+use colors::{self, Color};
+use game::{message, Messages};
+
+// The player index
+pub const PLAYER: usize = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeathCallback {
+    Player,
+    Monster,
+}
+
+/// What using an item does, with the numbers of the effect
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Item {
+    /// Gives the player back some hit points
+    Heal { amount: i32 },
+    /// Strikes the nearest monster in view within range
+    Lightning { damage: i32, range: f32 },
+    /// Burns everything within the radius around a tile picked by the player
+    Fireball { damage: i32, radius: f32, range: f32 },
+    /// Confuses a monster picked by the player, or the nearest one in view, for a number of turns
+    Confuse { duration: i32, range: f32 },
+    /// Worn by the player, see `Equippable`
+    Equip,
+}
+
+/// What the player picks before an item is used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Targeting {
+    Nothing,
+    /// Any tile in view within range
+    Tile,
+    /// A monster in view within range
+    Monster,
+}
+
+impl Item {
+    pub fn targeting(self) -> Targeting {
+        match self {
+            Item::Fireball { .. } => Targeting::Tile,
+            Item::Confuse { .. } => Targeting::Monster,
+            Item::Heal { .. } | Item::Lightning { .. } | Item::Equip => Targeting::Nothing,
+        }
+    }
+
+    /// How far from the player the item reaches, None when it only affects the player
+    pub fn range(self) -> Option<f32> {
+        match self {
+            Item::Heal { .. } | Item::Equip => None,
+            Item::Lightning { range, .. } | Item::Fireball { range, .. } | Item::Confuse { range, .. } => Some(range),
+        }
+    }
+
+    /// Check the numbers of the effect, returns the reason they are invalid
+    pub fn validate(self) -> Result<(), String> {
+        let (amounts, range, radius) = match self {
+            Item::Heal { amount } => (amount, None, None),
+            Item::Lightning { damage, range } => (damage, Some(range), None),
+            Item::Fireball { damage, radius, range } => (damage, Some(range), Some(radius)),
+            Item::Confuse { duration, range } => (duration, Some(range), None),
+            Item::Equip => return Ok(()),
+        };
+        if amounts <= 0 {
+            return Err("the amount, damage or duration of the effect must be positive".into());
+        }
+        if range.is_some_and(|range| range <= 0.0) {
+            return Err("the range of the effect must be positive".into());
+        }
+        if radius.is_some_and(|radius| radius < 0.0) {
+            return Err("the radius of the effect can not be negative".into());
+        }
+        Ok(())
+    }
+}
+
+impl DeathCallback {
+    fn callback(self, object: &mut Object, messages: &mut Messages) {
+        use self::DeathCallback::*;
+        let callback: fn(&mut Object, &mut Messages) = match self {
+            Player => player_death,
+            Monster => monster_death,
+        };
+        callback(object, messages);
+    }
+}
+
+/// Stats before equipment bonuses, see `Object::power` and friends for the effective ones
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fighter {
+    pub base_max_hp: i32,
+    pub hp: i32,
+    pub base_defense: i32,
+    pub base_power: i32,
+    /// Experience the player has gained, or that a monster is worth when killed
+    pub xp: i32,
+    pub on_death: DeathCallback,
+}
+
+/// The part of the body an item is worn on
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Slot {
+    Weapon,
+    Shield,
+    Armor,
+    Head,
+    Ring,
+}
+
+impl Slot {
+    /// How many items can be worn in the slot at once
+    pub fn capacity(self) -> usize {
+        match self {
+            Slot::Ring => 2,
+            Slot::Weapon | Slot::Shield | Slot::Armor | Slot::Head => 1,
+        }
+    }
+}
+
+/// An item that can be worn, and the bonuses it gives while it is
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Equippable {
+    pub slot: Slot,
+    #[serde(default)]
+    pub power_bonus: i32,
+    #[serde(default)]
+    pub defense_bonus: i32,
+    #[serde(default)]
+    pub max_hp_bonus: i32,
+}
+
+/// The items an object is wearing, at most the capacity of each slot
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Equipment {
+    pub items: Vec<Object>,
+}
+
+impl Equipment {
+    /// The items worn in a slot
+    pub fn in_slot(&self, slot: Slot) -> impl Iterator<Item = &Object> {
+        self.items.iter().filter(move |item| item.equippable.is_some_and(|e| e.slot == slot))
+    }
+}
+
+/// What a monster is doing, see the `ai` module for how it changes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Ai {
+    /// Does nothing until it notices the player, hears a fight or gets hurt
+    Asleep,
+    /// Roams around at random
+    Wandering,
+    /// Chases the player, or goes to where the player was last seen
+    Hunting { last_seen: (i32, i32) },
+    /// Runs away from the player, badly hurt
+    Fleeing,
+    /// Stumbles around for a number of turns, then goes back to what it was doing
+    Confused { previous: Box<Ai>, num_turns: i32 },
+}
+
+/// How a monster closes in on the player
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tactics {
+    /// Straight at the player, along the shortest path
+    Direct,
+    /// Spread out over the tiles around the player, so that a pack surrounds it
+    Pack,
+    /// Keep some distance from the player
+    Coward,
+}
+
+/// An object in the game
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Object {
+    pub x: i32,
+    pub y: i32,
+    pub char: char,
+    pub color: Color,
+    pub name: String,
+    pub blocks: bool,
+    pub alive: bool,
+    /// Character level, only the player gains levels
+    pub level: i32,
+    /// Drawn once explored, even outside of the field of view
+    pub always_visible: bool,
+    pub fighter: Option<Fighter>,
+    pub ai: Option<Ai>,
+    /// Only used by monsters with an AI
+    pub tactics: Tactics,
+    pub item: Option<Item>,
+    /// Set on items that can be worn
+    pub equippable: Option<Equippable>,
+    /// What the object is wearing, if it can wear anything
+    pub equipment: Option<Equipment>,
+    /// Damage dealt to a monster hit by the item when thrown, None when it does no harm
+    pub throw_damage: Option<i32>,
+    /// How many of the item are stacked in this object, see `stacks_with`
+    pub count: i32,
+    /// Leads down to the next level
+    pub stairs: bool,
+}
+
+/// Implementation of the object
+impl Object {
+    /// Create the player character
+    pub fn player() -> Self {
+        let mut player = Object::new(0, 0, '@', "player", colors::WHITE, true);
+        player.alive = true;
+        player.fighter = Some(Fighter { base_max_hp: 30, hp: 30, base_defense: 2, base_power: 5, xp: 0, on_death: DeathCallback::Player });
+        player.equipment = Some(Equipment::default());
+        player
+    }
+
+    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color, blocks: bool) -> Self {
+        Object {
+            x,
+            y,
+            char,
+            color,
+            name: name.into(),
+            blocks,
+            alive: false,
+            level: 1,
+            always_visible: false,
+            fighter: None,
+            ai: None,
+            tactics: Tactics::Direct,
+            item: None,
+            equippable: None,
+            equipment: None,
+            throw_damage: None,
+            count: 1,
+            stairs: false,
+        }
+    }
+
+    /// Create the stairs down to the next level
+    pub fn stairs(x: i32, y: i32) -> Self {
+        let mut stairs = Object::new(x, y, '>', "stairs", colors::WHITE, false);
+        stairs.always_visible = true;
+        stairs.stairs = true;
+        stairs
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn set_pos(&mut self, x: i32, y: i32) {
+        self.x = x;
+        self.y = y;
+    }
+
+    /// Take damage dealt from the given position, a monster that survives it turns to face it
+    pub fn take_damage(&mut self, damage: i32, from: (i32, i32), messages: &mut Messages) {
+        // apply damage if possible
+        if let Some(fighter) = self.fighter.as_mut() {
+            if damage > 0 {
+                fighter.hp -= damage;
+            }
+        }
+
+        // check for death, call the death function
+        if let Some(fighter) = self.fighter {
+            if fighter.hp <= 0 {
+                self.alive = false;
+                fighter.on_death.callback(self, messages);
+            } else if let Some(current) = self.ai.take() {
+                self.ai = Some(ai::hurt(current, fighter.hp, self.max_hp(), from));
+            }
+        }
+    }
+
+    /// Attack the target, returns the damage that was dealt
+    pub fn attack(&mut self, target: &mut Object, messages: &mut Messages) -> i32 {
+        // a simple formula for attack damage
+        let damage = self.power() - target.defense();
+        if damage > 0 {
+            // Make the target take some damage
+            message(messages, format!("{} attacks {} for {} hit points", self.name, target.name, damage), self.color);
+            target.take_damage(damage, self.pos(), messages);
+            damage
+        } else {
+            message(messages, format!("{} attack {} but it has no effect!", self.name, target.name), colors::DARK_YELLOW);
+            0
+        }
+    }
+
+    pub fn heal(&mut self, amount: i32) {
+        let max_hp = self.max_hp();
+        if let Some(ref mut figher) = self.fighter {
+            figher.hp += amount;
+            if figher.hp > max_hp {
+                figher.hp = max_hp;
+            }
+        }
+    }
+
+    /// Attack power including the bonuses of the equipment
+    pub fn power(&self) -> i32 {
+        self.fighter.map_or(0, |f| f.base_power) + self.equipment_bonus(|bonus| bonus.power_bonus)
+    }
+
+    /// Defense including the bonuses of the equipment
+    pub fn defense(&self) -> i32 {
+        self.fighter.map_or(0, |f| f.base_defense) + self.equipment_bonus(|bonus| bonus.defense_bonus)
+    }
+
+    /// Maximum hit points including the bonuses of the equipment
+    pub fn max_hp(&self) -> i32 {
+        self.fighter.map_or(0, |f| f.base_max_hp) + self.equipment_bonus(|bonus| bonus.max_hp_bonus)
+    }
+
+    /// The sum of one of the bonuses over everything the object wears
+    fn equipment_bonus<F: Fn(&Equippable) -> i32>(&self, bonus: F) -> i32 {
+        self.equipment.iter()
+            .flat_map(|equipment| equipment.items.iter())
+            .filter_map(|item| item.equippable.as_ref())
+            .map(bonus)
+            .sum()
+    }
+
+    /// Wear an item, taking off whatever it replaces in a full slot. Returns the item that
+    /// was taken off, or gives the item back when it cannot be worn.
+    #[allow(clippy::result_large_err)]
+    pub fn equip(&mut self, item: Object) -> Result<Option<Object>, Object> {
+        let slot = match item.equippable {
+            Some(equippable) => equippable.slot,
+            None => return Err(item),
+        };
+        let equipment = match self.equipment {
+            Some(ref mut equipment) => equipment,
+            None => return Err(item),
+        };
+        let mut removed = None;
+        if equipment.in_slot(slot).count() >= slot.capacity() {
+            // the item that was put on first makes room
+            let index = equipment.items.iter()
+                .position(|worn| worn.equippable.is_some_and(|e| e.slot == slot))
+                .expect("a full slot holds an item");
+            removed = Some(equipment.items.remove(index));
+        }
+        equipment.items.push(item);
+        self.clamp_hp();
+        Ok(removed)
+    }
+
+    /// Take off the worn item at the given index of the equipment
+    pub fn unequip(&mut self, index: usize) -> Option<Object> {
+        let item = self.equipment.as_mut()
+            .filter(|equipment| index < equipment.items.len())
+            .map(|equipment| equipment.items.remove(index));
+        self.clamp_hp();
+        item
+    }
+
+    /// Make sure the hit points do not exceed the maximum, after the bonuses changed
+    fn clamp_hp(&mut self) {
+        let max_hp = self.max_hp();
+        if let Some(ref mut fighter) = self.fighter {
+            fighter.hp = cmp::min(fighter.hp, max_hp);
+        }
+    }
+
+    /// Whether the two objects are identical items that can share a stack. Worn items
+    /// never stack, so that each one can be put on by itself.
+    pub fn stacks_with(&self, other: &Object) -> bool {
+        self.item.is_some() && self.equippable.is_none() && other.equippable.is_none() &&
+            self.item == other.item && self.name == other.name && self.char == other.char &&
+            self.color == other.color && self.throw_damage == other.throw_damage
+    }
+
+    /// The name with the size of the stack, "3 healing potions"
+    pub fn display_name(&self) -> String {
+        if self.count == 1 {
+            return self.name.clone();
+        }
+        // the plural goes on the first word of "scroll of fireball"
+        let plural = match self.name.find(" of ") {
+            Some(index) => format!("{}s{}", &self.name[..index], &self.name[index..]),
+            None => format!("{}s", self.name),
+        };
+        format!("{} {}", self.count, plural)
+    }
+
+    /// How hurt a living fighter looks
+    pub fn health(&self) -> Option<&'static str> {
+        let fighter = self.fighter.filter(|_| self.alive)?;
+        let fraction = fighter.hp as f32 / cmp::max(1, self.max_hp()) as f32;
+        Some(if fraction >= 1.0 {
+            "unhurt"
+        } else if fraction > 0.75 {
+            "lightly wounded"
+        } else if fraction > 0.5 {
+            "wounded"
+        } else if fraction > 0.25 {
+            "badly wounded"
+        } else {
+            "almost dead"
+        })
+    }
+
+    /// What the player sees in the object: the state of a monster or what an item does
+    pub fn description(&self) -> String {
+        let mut details: Vec<String> = vec![];
+        if let Some(health) = self.health() {
+            details.push(health.into());
+        }
+        match self.ai {
+            Some(Ai::Asleep) => details.push("asleep".into()),
+            Some(Ai::Fleeing) => details.push("fleeing".into()),
+            Some(Ai::Confused { .. }) => details.push("confused".into()),
+            _ => {}
+        }
+        if let Some(equippable) = self.equippable {
+            let bonuses = [(equippable.power_bonus, "attack"), (equippable.defense_bonus, "defense"),
+                           (equippable.max_hp_bonus, "HP")];
+            for &(bonus, stat) in bonuses.iter().filter(|&&(bonus, _)| bonus != 0) {
+                details.push(format!("{:+} {}", bonus, stat));
+            }
+        }
+        if let Some(damage) = self.throw_damage {
+            details.push(format!("{} damage when thrown", damage));
+        }
+        if details.is_empty() {
+            self.display_name()
+        } else {
+            format!("{}: {}", self.display_name(), details.join(", "))
+        }
+    }
+
+    pub fn distance_to(&self, other: &Object) -> f32 {
+        self.distance(other.x, other.y)
+    }
+
+    /// Distance to a tile
+    pub fn distance(&self, x: i32, y: i32) -> f32 {
+        (((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
+    }
+}
+
+fn player_death(player: &mut Object, messages: &mut Messages) {
+    // The game ended
+    message(messages, "You died!", colors::RED);
+    player.char = '%';
+    player.color = colors::DARK_RED;
+}
+
+fn monster_death(monster: &mut Object, messages: &mut Messages) {
+    // Transform it into a nasty corpse, it doesn't block, can't be attacked
+    // and doesn't move
+    message(messages, format!("{} is dead!", monster.name), colors::WHITE);
+    monster.char = '%';
+    monster.color = colors::DARK_RED;
+    monster.blocks = false;
+    monster.ai = None;
+    monster.alive = false;
+    monster.name = format!("remains of {}", monster.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orc(hp: i32) -> Object {
+        let mut orc = Object::new(0, 0, 'o', "orc", colors::WHITE, true);
+        orc.fighter = Some(Fighter { base_max_hp: 20, hp, base_defense: 0, base_power: 3, xp: 35,
+                                     on_death: DeathCallback::Monster });
+        orc.ai = Some(Ai::Wandering);
+        orc.alive = true;
+        orc
+    }
+
+    #[test]
+    fn health_gets_worse_in_bands() {
+        let bands = [(20, "unhurt"), (16, "lightly wounded"), (15, "wounded"), (11, "wounded"),
+                     (10, "badly wounded"), (6, "badly wounded"), (5, "almost dead"), (1, "almost dead")];
+        for &(hp, health) in &bands {
+            assert_eq!(orc(hp).health(), Some(health), "{} hp", hp);
+        }
+        let mut dead = orc(0);
+        dead.take_damage(1, (1, 0), &mut Messages::default());
+        assert_eq!(dead.health(), None);
+        assert_eq!(Object::new(0, 0, '!', "potion", colors::WHITE, false).health(), None);
+    }
+
+    #[test]
+    fn stacks_are_named_in_the_plural() {
+        let mut scroll = Object::new(0, 0, '#', "scroll of fireball", colors::WHITE, false);
+        assert_eq!(scroll.display_name(), "scroll of fireball");
+        scroll.count = 3;
+        assert_eq!(scroll.display_name(), "3 scrolls of fireball");
+        let mut dagger = Object::new(0, 0, '-', "dagger", colors::WHITE, false);
+        dagger.count = 2;
+        assert_eq!(dagger.display_name(), "2 daggers");
+    }
+
+    #[test]
+    fn descriptions_list_what_the_player_can_tell() {
+        let mut monster = orc(8);
+        assert_eq!(monster.description(), "orc: badly wounded");
+        monster.ai = Some(Ai::Asleep);
+        assert_eq!(monster.description(), "orc: badly wounded, asleep");
+        monster.ai = Some(Ai::Confused { previous: Box::new(Ai::Wandering), num_turns: 3 });
+        assert_eq!(monster.description(), "orc: badly wounded, confused");
+
+        let mut sword = Object::new(0, 0, '/', "sword", colors::WHITE, false);
+        assert_eq!(sword.description(), "sword");
+        sword.equippable = Some(Equippable { slot: Slot::Weapon, power_bonus: 3, defense_bonus: -1,
+                                             max_hp_bonus: 0 });
+        sword.throw_damage = Some(2);
+        assert_eq!(sword.description(), "sword: +3 attack, -1 defense, 2 damage when thrown");
+    }
+}
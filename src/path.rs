@@ -0,0 +1,286 @@
+//! Path finding over the tile grid.
+
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+
+use map::{MAP_HEIGHT, MAP_WIDTH};
+
+/// The eight directions an object can step in
+pub const DIRECTIONS: [(i32, i32); 8] = [
+    (-1, -1), (0, -1), (1, -1),
+    (-1, 0), (1, 0),
+    (-1, 1), (0, 1), (1, 1),
+];
+
+/// Find a shortest path from start to goal with A*, stepping in all eight directions.
+/// `blocked` tells which tiles cannot be entered, the goal is always allowed so that
+/// a path can lead up to a blocking target.
+/// Returns the tiles to step on after the start, ending with the goal.
+pub fn astar<F>(start: (i32, i32), goal: (i32, i32), blocked: F) -> Option<Vec<(i32, i32)>>
+    where F: Fn(i32, i32) -> bool
+{
+    // with diagonal steps costing the same as straight ones, this never overestimates
+    let heuristic = |(x, y): (i32, i32)| cmp::max((goal.0 - x).abs(), (goal.1 - y).abs());
+    let index = |(x, y): (i32, i32)| (x + y * MAP_WIDTH) as usize;
+
+    let size = (MAP_WIDTH * MAP_HEIGHT) as usize;
+    let mut cost_so_far = vec![i32::MAX; size];
+    let mut came_from: Vec<Option<(i32, i32)>> = vec![None; size];
+    let mut open = BinaryHeap::new();
+
+    cost_so_far[index(start)] = 0;
+    open.push((Reverse(heuristic(start)), start));
+
+    while let Some((Reverse(estimate), current)) = open.pop() {
+        let cost = cost_so_far[index(current)];
+        if estimate > cost + heuristic(current) {
+            // a cheaper way to this tile was found after it was queued
+            continue;
+        }
+        if current == goal {
+            // walk back to the start to recover the path
+            let mut path = vec![goal];
+            let mut tile = goal;
+            while let Some(previous) = came_from[index(tile)] {
+                if previous == start {
+                    break;
+                }
+                path.push(previous);
+                tile = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for &(dx, dy) in &DIRECTIONS {
+            let next = (current.0 + dx, current.1 + dy);
+            if next.0 < 0 || next.1 < 0 || next.0 >= MAP_WIDTH || next.1 >= MAP_HEIGHT {
+                continue;
+            }
+            if next != goal && blocked(next.0, next.1) {
+                continue;
+            }
+            let next_cost = cost + 1;
+            if next_cost < cost_so_far[index(next)] {
+                cost_so_far[index(next)] = next_cost;
+                came_from[index(next)] = Some(current);
+                open.push((Reverse(next_cost + heuristic(next)), next));
+            }
+        }
+    }
+    None
+}
+
+/// The tiles on a straight line from start to end, without the start
+pub fn line((start_x, start_y): (i32, i32), end: (i32, i32)) -> Line {
+    let dx = (end.0 - start_x).abs();
+    let dy = -(end.1 - start_y).abs();
+    Line {
+        current: (start_x, start_y),
+        end,
+        dx,
+        dy,
+        step: (if start_x < end.0 { 1 } else { -1 }, if start_y < end.1 { 1 } else { -1 }),
+        error: dx + dy,
+    }
+}
+
+/// A bresenham line walked one tile at a time, stepping diagonally where needed
+pub struct Line {
+    current: (i32, i32),
+    end: (i32, i32),
+    dx: i32,
+    dy: i32,
+    step: (i32, i32),
+    error: i32,
+}
+
+impl Iterator for Line {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<(i32, i32)> {
+        if self.current == self.end {
+            return None;
+        }
+        let double_error = 2 * self.error;
+        if double_error >= self.dy {
+            self.error += self.dy;
+            self.current.0 += self.step.0;
+        }
+        if double_error <= self.dx {
+            self.error += self.dx;
+            self.current.1 += self.step.1;
+        }
+        Some(self.current)
+    }
+}
+
+/// The cost of one step on a Dijkstra map, in tenths so that scaled maps stay integers
+pub const STEP_COST: i32 = 10;
+
+/// How much further than the player's reach a fleeing monster tries to get, in tenths.
+/// Above 10 a monster prefers running past the player to a far away exit over being
+/// cornered in a dead end.
+const FLEE_FACTOR: i32 = 12;
+
+/// Distances over the tile grid to the nearest of a set of goals, in tenths of a step.
+///
+/// Walking downhill leads to a goal. Several monsters can share one map, which only has
+/// to be recomputed when the goals change.
+#[derive(Clone, Debug, Default)]
+pub struct DijkstraMap {
+    values: Vec<i32>,
+}
+
+impl DijkstraMap {
+    /// A map leading to the goals, `blocked` tells which tiles cannot be walked on
+    pub fn new<F>(goals: &[(i32, i32)], blocked: F) -> Self
+        where F: Fn(i32, i32) -> bool
+    {
+        let mut values = vec![i32::MAX; (MAP_WIDTH * MAP_HEIGHT) as usize];
+        for &(x, y) in goals {
+            values[(x + y * MAP_WIDTH) as usize] = 0;
+        }
+        DijkstraMap { values: scan(values, blocked) }
+    }
+
+    /// A map leading away from the goals of this map. Walking downhill on it flees,
+    /// towards the tiles that are furthest away but without running into dead ends.
+    pub fn fleeing<F>(&self, blocked: F) -> Self
+        where F: Fn(i32, i32) -> bool
+    {
+        let values = self.values.iter()
+            .map(|&value| if value == i32::MAX { value } else { -value * FLEE_FACTOR / 10 })
+            .collect();
+        DijkstraMap { values: scan(values, blocked) }
+    }
+
+    /// The value of a tile, None when it cannot reach any goal
+    pub fn value(&self, x: i32, y: i32) -> Option<i32> {
+        if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT {
+            return None;
+        }
+        self.values.get((x + y * MAP_WIDTH) as usize)
+            .cloned()
+            .filter(|&value| value != i32::MAX)
+    }
+}
+
+/// Lower every tile to at most one step more than its lowest neighbour
+fn scan<F>(mut values: Vec<i32>, blocked: F) -> Vec<i32>
+    where F: Fn(i32, i32) -> bool
+{
+    // Dijkstra from every tile with a value, so start tiles can have any value
+    let mut open: BinaryHeap<_> = values.iter().enumerate()
+        .filter(|&(_, &value)| value != i32::MAX)
+        .map(|(index, &value)| (Reverse(value), index as i32 % MAP_WIDTH, index as i32 / MAP_WIDTH))
+        .collect();
+
+    while let Some((Reverse(value), x, y)) = open.pop() {
+        if value > values[(x + y * MAP_WIDTH) as usize] {
+            continue;
+        }
+        for &(dx, dy) in &DIRECTIONS {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= MAP_WIDTH || ny >= MAP_HEIGHT || blocked(nx, ny) {
+                continue;
+            }
+            let index = (nx + ny * MAP_WIDTH) as usize;
+            if value + STEP_COST < values[index] {
+                values[index] = value + STEP_COST;
+                open.push((Reverse(value + STEP_COST), nx, ny));
+            }
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A wall at x = 5 from the top of the map down to y = 9
+    fn wall(x: i32, y: i32) -> bool {
+        x == 5 && y < 10
+    }
+
+    fn is_walk(start: (i32, i32), path: &[(i32, i32)]) -> bool {
+        let mut tiles = vec![start];
+        tiles.extend_from_slice(path);
+        tiles.windows(2).all(|step| {
+            let (dx, dy) = (step[1].0 - step[0].0, step[1].1 - step[0].1);
+            dx.abs() <= 1 && dy.abs() <= 1 && (dx, dy) != (0, 0)
+        })
+    }
+
+    #[test]
+    fn lines_step_diagonally_and_end_on_the_target() {
+        assert_eq!(line((0, 0), (3, 0)).collect::<Vec<_>>(), vec![(1, 0), (2, 0), (3, 0)]);
+        assert_eq!(line((0, 0), (-2, -2)).collect::<Vec<_>>(), vec![(-1, -1), (-2, -2)]);
+        assert_eq!(line((0, 0), (4, 2)).collect::<Vec<_>>(), vec![(1, 1), (2, 1), (3, 2), (4, 2)]);
+        assert_eq!(line((5, 5), (5, 5)).count(), 0);
+    }
+
+    #[test]
+    fn astar_finds_a_shortest_path_in_the_open() {
+        let path = astar((0, 0), (5, 3), |_, _| false).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(path.last(), Some(&(5, 3)));
+        assert!(is_walk((0, 0), &path));
+    }
+
+    #[test]
+    fn astar_goes_around_walls() {
+        let path = astar((2, 2), (8, 2), wall).unwrap();
+        assert_eq!(path.len(), 16);
+        assert!(path.iter().all(|&(x, y)| !wall(x, y)));
+        assert!(is_walk((2, 2), &path));
+    }
+
+    #[test]
+    fn astar_can_end_on_a_blocked_goal() {
+        let path = astar((2, 2), (5, 5), wall).unwrap();
+        assert_eq!(path.len(), 3);
+        assert_eq!(path.last(), Some(&(5, 5)));
+    }
+
+    #[test]
+    fn astar_gives_up_on_an_enclosed_goal() {
+        let enclosed = |x: i32, y: i32| (x - 20).abs() <= 1 && (y - 20).abs() <= 1 && (x, y) != (20, 20);
+        assert_eq!(astar((2, 2), (20, 20), enclosed), None);
+    }
+
+    #[test]
+    fn dijkstra_values_count_steps_to_the_nearest_goal() {
+        let map = DijkstraMap::new(&[(10, 10), (30, 10)], |_, _| false);
+        assert_eq!(map.value(10, 10), Some(0));
+        assert_eq!(map.value(11, 11), Some(STEP_COST));
+        assert_eq!(map.value(14, 10), Some(4 * STEP_COST));
+        assert_eq!(map.value(27, 12), Some(3 * STEP_COST));
+        assert_eq!(map.value(-1, 10), None);
+    }
+
+    #[test]
+    fn dijkstra_values_go_around_walls() {
+        let map = DijkstraMap::new(&[(2, 2)], wall);
+        assert_eq!(map.value(8, 2), Some(16 * STEP_COST));
+        assert_eq!(map.value(5, 2), None);
+    }
+
+    #[test]
+    fn dijkstra_values_are_missing_where_no_goal_can_be_reached() {
+        let enclosed = |x: i32, y: i32| (x - 20).abs() <= 1 && (y - 20).abs() <= 1 && (x, y) != (20, 20);
+        let map = DijkstraMap::new(&[(2, 2)], enclosed);
+        assert_eq!(map.value(20, 20), None);
+        assert!(map.value(22, 22).is_some());
+    }
+
+    #[test]
+    fn fleeing_leads_downhill_away_from_the_goals() {
+        let approach = DijkstraMap::new(&[(40, 20)], |_, _| false);
+        let flee = approach.fleeing(|_, _| false);
+        assert!(flee.value(41, 20) > flee.value(42, 20));
+        assert!(flee.value(42, 20) > flee.value(60, 20));
+        assert!(flee.value(60, 20) > flee.value(79, 20));
+    }
+}
@@ -0,0 +1,158 @@
+//! Saving and loading games.
+//!
+//! A save file is JSON holding a format version next to the game state. The version is
+//! checked before anything else is read, so a save made by an older build gives a clear
+//! error instead of a parse failure somewhere in the middle of the map.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use serde_json;
+
+use fov::FovMap;
+use game::Game;
+
+/// Version of the save format, bump this when the layout of the saved state changes
+pub const SAVE_VERSION: u32 = 13;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    /// The save was written with a different format version
+    Version { found: Option<u32>, expected: u32 },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveError::Io(ref err) => write!(f, "Could not access the save file: {}", err),
+            SaveError::Format(ref err) => write!(f, "The save file is corrupt: {}", err),
+            SaveError::Version { found: Some(found), expected } =>
+                write!(f, "The save file has version {}, but this game can only load version {}",
+                       found, expected),
+            SaveError::Version { found: None, expected } =>
+                write!(f, "The save file has no version, but this game can only load version {}",
+                       expected),
+        }
+    }
+}
+
+impl Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(err: serde_json::Error) -> Self {
+        SaveError::Format(err)
+    }
+}
+
+#[derive(Serialize)]
+struct SaveFile<'a> {
+    version: u32,
+    game: &'a Game,
+}
+
+/// Only the version, so it can be checked before the rest of the save is parsed
+#[derive(Deserialize)]
+struct SaveHeader {
+    version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct LoadFile {
+    game: Game,
+}
+
+pub fn save_game(game: &Game, path: &Path) -> Result<(), SaveError> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, &SaveFile { version: SAVE_VERSION, game })?;
+    Ok(())
+}
+
+pub fn load_game(path: &Path) -> Result<Game, SaveError> {
+    let header: SaveHeader = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if header.version != Some(SAVE_VERSION) {
+        return Err(SaveError::Version { found: header.version, expected: SAVE_VERSION });
+    }
+
+    let LoadFile { mut game } = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    // rebuild the state that is not saved
+    game.fov_map = FovMap::from_map(&game.map);
+    game.compute_fov();
+    Ok(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    use game::Action;
+    use map::MapGenerator;
+    use object::{Ai, PLAYER};
+
+    /// A new game where every monster wanders around at random from the start, and the
+    /// player lives long enough to meet them
+    fn wandering(seed: u64) -> Game {
+        let mut game = Game::new(seed, MapGenerator::Rooms);
+        if let Some(ref mut fighter) = game.objects[PLAYER].fighter {
+            fighter.base_max_hp = 10_000;
+            fighter.hp = 10_000;
+        }
+        for object in &mut game.objects {
+            if object.ai.is_some() {
+                object.ai = Some(Ai::Wandering);
+            }
+        }
+        game
+    }
+
+    /// Walk around in circles, 16 turns a lap
+    fn play(game: &mut Game, turns: u32) {
+        for turn in 0..turns {
+            let dx = [1, 0, -1, 0][(turn / 4 % 4) as usize];
+            let dy = [0, 1, 0, -1][(turn / 4 % 4) as usize];
+            game.step(Action::Move(dx, dy));
+        }
+    }
+
+    #[test]
+    fn a_loaded_game_plays_on_like_one_that_was_never_saved() {
+        let path = env::temp_dir().join(format!("roguelike-save-test-{}.json", std::process::id()));
+        let mut uninterrupted = wandering(3);
+        play(&mut uninterrupted, 64);
+
+        let mut saved = wandering(3);
+        play(&mut saved, 32);
+        save_game(&saved, &path).unwrap();
+        let mut loaded = load_game(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        play(&mut loaded, 32);
+
+        assert!(loaded.objects[PLAYER].alive);
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&uninterrupted).unwrap());
+    }
+
+    #[test]
+    fn saves_of_another_version_are_refused() {
+        let path = env::temp_dir().join(format!("roguelike-version-test-{}.json", std::process::id()));
+        fs::write(&path, r#"{"version": 0, "game": {}}"#).unwrap();
+        let result = load_game(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(SaveError::Version { found: Some(0), expected: SAVE_VERSION }) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("an old save was loaded"),
+        }
+    }
+}
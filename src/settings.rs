@@ -0,0 +1,231 @@
+//! Display settings: the font, the size and speed of the window, and how long the message
+//! log is.
+//!
+//! A settings file is JSON, for instance `{ "font": "fonts/terminal10x16_gs_tc.png",
+//! "screen_width": 120 }`. Settings left out keep their default, and command line flags
+//! override the file. Without a font setting the font bundled in `fonts/` is used.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_json;
+
+use game::{MESSAGE_HISTORY, MSG_HEIGHT};
+use map::{MAP_HEIGHT, MAP_WIDTH};
+
+/// The bundled font, relative to the working directory, the executable or the source
+pub const FONT_FILE: &str = "fonts/consolas12x12_gs_tc.png";
+/// The window has to fit the map
+pub const MIN_SCREEN_WIDTH: i32 = MAP_WIDTH;
+/// Rows below the map that describe what the player looks at, a tile with a few objects
+pub const LOOK_ROWS: i32 = 4;
+/// The map, a line of help below it, room to describe what is looked at, and the panel
+/// with the messages
+pub const MIN_SCREEN_HEIGHT: i32 = MAP_HEIGHT + 1 + LOOK_ROWS + MSG_HEIGHT as i32 + 1;
+
+/// How the characters are laid out in the font image
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FontLayout {
+    Tcod,
+    AsciiInCol,
+    AsciiInRow,
+}
+
+impl FromStr for FontLayout {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "tcod" => Ok(FontLayout::Tcod),
+            "ascii_in_col" => Ok(FontLayout::AsciiInCol),
+            "ascii_in_row" => Ok(FontLayout::AsciiInRow),
+            _ => Err(format!("Unknown font layout: {}, expected tcod, ascii_in_col or ascii_in_row", name)),
+        }
+    }
+}
+
+/// Whether the font image is greyscale, or uses its alpha channel
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FontType {
+    Default,
+    Greyscale,
+}
+
+impl FromStr for FontType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "default" => Ok(FontType::Default),
+            "greyscale" => Ok(FontType::Greyscale),
+            _ => Err(format!("Unknown font type: {}, expected default or greyscale", name)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    /// A setting given a value it cannot take
+    Invalid { setting: String, reason: String },
+    /// The font is in none of the places looked at
+    FontNotFound(Vec<PathBuf>),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SettingsError::Io(ref err) => write!(f, "Could not read the settings: {}", err),
+            SettingsError::Format(ref err) => write!(f, "The settings are not valid: {}", err),
+            SettingsError::Invalid { ref setting, ref reason } => write!(f, "{}: {}", setting, reason),
+            SettingsError::FontNotFound(ref paths) => {
+                write!(f, "The font could not be found, looked for:")?;
+                for path in paths {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                write!(f, "\nPick a font with --font, or with \"font\" in the settings file.")
+            }
+        }
+    }
+}
+
+impl Error for SettingsError {}
+
+impl From<io::Error> for SettingsError {
+    fn from(err: io::Error) -> Self {
+        SettingsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SettingsError {
+    fn from(err: serde_json::Error) -> Self {
+        SettingsError::Format(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The font image, None for the bundled font
+    pub font: Option<PathBuf>,
+    pub font_layout: FontLayout,
+    pub font_type: FontType,
+    /// The size of the window, in characters
+    pub screen_width: i32,
+    pub screen_height: i32,
+    /// The most frames drawn per second, 0 for no limit
+    pub fps: i32,
+    /// How many messages the log keeps, older ones are dropped
+    pub message_history: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            font: None,
+            font_layout: FontLayout::Tcod,
+            font_type: FontType::Greyscale,
+            screen_width: 100,
+            screen_height: 60,
+            fps: 20,
+            message_history: MESSAGE_HISTORY,
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings file at the path, with defaults for what it leaves out
+    pub fn load(path: &Path) -> Result<Self, SettingsError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Change the setting with the given name, as it is called in the settings file
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), SettingsError> {
+        let invalid = |reason: String| SettingsError::Invalid { setting: setting.into(), reason };
+        let number = |value: &str| value.parse::<i32>()
+            .map_err(|_| invalid(format!("{} is not a number", value)));
+        match setting {
+            "font" => self.font = Some(PathBuf::from(value)),
+            "font_layout" => self.font_layout = value.parse().map_err(invalid)?,
+            "font_type" => self.font_type = value.parse().map_err(invalid)?,
+            "screen_width" => self.screen_width = number(value)?,
+            "screen_height" => self.screen_height = number(value)?,
+            "fps" => self.fps = number(value)?,
+            "message_history" => self.message_history = value.parse()
+                .map_err(|_| invalid(format!("{} is not a number of messages", value)))?,
+            _ => return Err(invalid("there is no such setting".into())),
+        }
+        Ok(())
+    }
+
+    /// Check that the window is big enough for the game, the frame rate is not negative and
+    /// the log keeps at least one message
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |setting: &str, reason: String| Err(SettingsError::Invalid { setting: setting.into(), reason });
+        if self.screen_width < MIN_SCREEN_WIDTH {
+            return invalid("screen_width", format!("{} is less than the minimum of {}",
+                                                   self.screen_width, MIN_SCREEN_WIDTH));
+        }
+        if self.screen_height < MIN_SCREEN_HEIGHT {
+            return invalid("screen_height", format!("{} is less than the minimum of {}",
+                                                    self.screen_height, MIN_SCREEN_HEIGHT));
+        }
+        if self.fps < 0 {
+            return invalid("fps", format!("{} is negative", self.fps));
+        }
+        if self.message_history == 0 {
+            return invalid("message_history", "the log has to keep at least one message".into());
+        }
+        Ok(())
+    }
+
+    /// The font image to load: the one set, or else the bundled font found relative to
+    /// the working directory, the executable or the source of the game
+    pub fn find_font(&self) -> Result<PathBuf, SettingsError> {
+        let candidates = match self.font {
+            Some(ref font) => vec![font.clone()],
+            None => {
+                let mut candidates = vec![PathBuf::from(FONT_FILE)];
+                if let Some(dir) = env::current_exe().ok().as_ref().and_then(|exe| exe.parent()) {
+                    candidates.push(dir.join(FONT_FILE));
+                }
+                candidates.push(Path::new(env!("CARGO_MANIFEST_DIR")).join(FONT_FILE));
+                candidates
+            }
+        };
+        match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => Ok(path.clone()),
+            None => Err(SettingsError::FontNotFound(candidates)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_defaults_fit_the_game() {
+        assert!(Settings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn the_screen_leaves_room_to_look_around() {
+        let mut settings = Settings::default();
+        settings.set("screen_height", &(MIN_SCREEN_HEIGHT - 1).to_string()).unwrap();
+        match settings.validate() {
+            Err(SettingsError::Invalid { ref setting, .. }) if setting == "screen_height" => {}
+            other => panic!("expected the screen to be too low, got {:?}", other),
+        }
+        settings.screen_height = MIN_SCREEN_HEIGHT;
+        assert!(settings.validate().is_ok());
+    }
+}
@@ -0,0 +1,307 @@
+//! Spawn tables: how much turns up on a level and how often each kind of monster and item
+//! does, depending on how deep the level is.
+//!
+//! Both are written as depth tables in the data files, so trolls can become common deeper
+//! down, and more potions can lie around there.
+
+use std::fmt;
+
+/// The most monsters, or items, a room can get. A small room has about this many free
+/// tiles, more would only slow down filling the level.
+pub const MAX_PER_ROOM: u32 = 20;
+
+/// A number that changes with the depth. In a data file it is a plain number for every depth,
+/// or a list of `[depth, value]` steps like `[[1, 20], [3, 30], [5, 60]]`: a value holds from
+/// its depth down to the next step, above the first step the value is 0.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "DepthTableEntry", into = "DepthTableEntry")]
+pub struct DepthTable {
+    steps: Vec<(u32, u32)>,
+}
+
+/// How a depth table is written in a data file
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged, expecting = "a number, or a list of [depth, value] steps")]
+enum DepthTableEntry {
+    Constant(u32),
+    Steps(Vec<(u32, u32)>),
+}
+
+impl From<DepthTableEntry> for DepthTable {
+    fn from(entry: DepthTableEntry) -> Self {
+        match entry {
+            DepthTableEntry::Constant(value) => DepthTable::constant(value),
+            DepthTableEntry::Steps(steps) => DepthTable { steps },
+        }
+    }
+}
+
+impl From<DepthTable> for DepthTableEntry {
+    fn from(table: DepthTable) -> Self {
+        DepthTableEntry::Steps(table.steps)
+    }
+}
+
+impl DepthTable {
+    /// The same value at every depth
+    pub fn constant(value: u32) -> Self {
+        DepthTable { steps: vec![(1, value)] }
+    }
+
+    /// The value at the depth: that of the deepest step at or above it
+    pub fn at(&self, depth: u32) -> u32 {
+        self.steps.iter()
+            .take_while(|&&(from, _)| from <= depth)
+            .last()
+            .map_or(0, |&(_, value)| value)
+    }
+
+    /// The highest value at any depth
+    pub fn highest(&self) -> u32 {
+        self.steps.iter().map(|&(_, value)| value).max().unwrap_or(0)
+    }
+
+    /// Check that the steps start at a level that exists and go down, returns the reason
+    /// the table is invalid
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err("a depth table needs at least one step".into());
+        }
+        if self.steps[0].0 == 0 {
+            return Err("the depths of a depth table start at 1".into());
+        }
+        if self.steps.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err("the steps of a depth table must go down, each deeper than the one before".into());
+        }
+        Ok(())
+    }
+}
+
+/// Where and how often a kind of monster or item turns up, the `spawn` object of an entry
+/// in a data file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spawn {
+    /// How often it turns up compared to the others of its sort, by depth
+    pub weight: DepthTable,
+    /// The shallowest level it turns up on
+    #[serde(default = "first_depth")]
+    pub min_depth: u32,
+    /// The deepest level it turns up on, None for all levels below the shallowest
+    #[serde(default)]
+    pub max_depth: Option<u32>,
+}
+
+fn first_depth() -> u32 {
+    1
+}
+
+impl Spawn {
+    /// Its weight on a level at the depth, 0 when it does not turn up there
+    pub fn weight_at(&self, depth: u32) -> u32 {
+        let below = self.max_depth.is_some_and(|max_depth| depth > max_depth);
+        if depth < self.min_depth || below {
+            return 0;
+        }
+        self.weight.at(depth)
+    }
+
+    /// The depths its weight can change at: the steps of its weight, the first level it turns
+    /// up on and the level below the last one
+    fn changes(&self) -> Vec<u32> {
+        let mut depths: Vec<u32> = self.weight.steps.iter().map(|&(depth, _)| depth).collect();
+        depths.push(self.min_depth);
+        depths.extend(self.max_depth.map(|max_depth| max_depth.saturating_add(1)));
+        depths
+    }
+
+    /// Check the weight, and that the levels it turns up on exist
+    pub fn validate(&self) -> Result<(), String> {
+        self.weight.validate().map_err(|reason| format!("weight: {}", reason))?;
+        if self.min_depth == 0 {
+            return Err("min_depth starts at 1".into());
+        }
+        match self.max_depth {
+            Some(max_depth) if max_depth < self.min_depth =>
+                Err(format!("max_depth {} is less than min_depth {}", max_depth, self.min_depth)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check that the weights of all the kinds of monsters, or of items, add up to at most
+/// `u32::MAX` at every depth, the most a weighted choice can pick from
+pub fn check_total_weight(spawns: &[&Spawn]) -> Result<(), String> {
+    for depth in spawns.iter().flat_map(|spawn| spawn.changes()) {
+        spawns.iter()
+            .try_fold(0u32, |total, spawn| total.checked_add(spawn.weight_at(depth)))
+            .ok_or_else(|| format!("the weights add up to more than {} at depth {}", u32::MAX, depth))?;
+    }
+    Ok(())
+}
+
+/// How much turns up in each room, or area of a cave
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpawnCounts {
+    /// Every room gets between none and this many monsters
+    pub max_monsters: DepthTable,
+    /// Every room gets between none and this many items
+    pub max_items: DepthTable,
+}
+
+impl SpawnCounts {
+    /// Check the depth tables, and that no room gets more than `MAX_PER_ROOM` of either
+    pub fn validate(&self) -> Result<(), String> {
+        for &(name, table) in &[("max_monsters", &self.max_monsters), ("max_items", &self.max_items)] {
+            table.validate().map_err(|reason| format!("{}: {}", name, reason))?;
+            if table.highest() > MAX_PER_ROOM {
+                return Err(format!("{}: at most {} per room", name, MAX_PER_ROOM));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to expect in a room at some depth, for balancing the spawn tables
+#[derive(Clone, Debug)]
+pub struct Distribution {
+    pub depth: u32,
+    pub max_monsters: u32,
+    pub max_items: u32,
+    /// Every kind of monster that turns up, with the chance a monster is of that kind
+    pub monsters: Vec<(String, f32)>,
+    /// Every kind of item that turns up, with the chance an item is of that kind
+    pub items: Vec<(String, f32)>,
+}
+
+impl Distribution {
+    /// The chance of every kind, from their weights at the depth. Kinds that do not turn up
+    /// are left out.
+    pub fn chances<'a, I>(weights: I) -> Vec<(String, f32)>
+        where I: IntoIterator<Item = (&'a String, u32)>
+    {
+        let weights: Vec<(&String, u32)> = weights.into_iter().filter(|&(_, weight)| weight > 0).collect();
+        let total: u64 = weights.iter().map(|&(_, weight)| u64::from(weight)).sum();
+        weights.into_iter()
+            .map(|(name, weight)| (name.clone(), weight as f32 / total as f32))
+            .collect()
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the number in a room is picked evenly from none up to the maximum
+        let mean_monsters = self.max_monsters as f32 / 2.0;
+        let mean_items = self.max_items as f32 / 2.0;
+        writeln!(f, "Depth {}: up to {} monsters and {} items per room, {:.1} and {:.1} on average",
+                 self.depth, self.max_monsters, self.max_items, mean_monsters, mean_items)?;
+        for &(title, kinds, mean) in &[("Monsters", &self.monsters, mean_monsters), ("Items", &self.items, mean_items)] {
+            writeln!(f, "{:<28} {:>7} {:>9}", title, "chance", "per room")?;
+            if kinds.is_empty() {
+                writeln!(f, "  none")?;
+            }
+            for &(ref name, chance) in kinds.iter() {
+                writeln!(f, "  {:<26} {:>6.1}% {:>9.2}", name, chance * 100.0, chance * mean)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn(weight: DepthTable, min_depth: u32, max_depth: Option<u32>) -> Spawn {
+        Spawn { weight, min_depth, max_depth }
+    }
+
+    #[test]
+    fn nothing_turns_up_outside_its_depths() {
+        let spawn = spawn(DepthTable::constant(10), 2, Some(4));
+        assert_eq!(spawn.weight_at(1), 0);
+        assert_eq!(spawn.weight_at(2), 10);
+        assert_eq!(spawn.weight_at(4), 10);
+        assert_eq!(spawn.weight_at(5), 0);
+    }
+
+    #[test]
+    fn spawns_are_checked_for_levels_that_exist() {
+        assert!(spawn(DepthTable::constant(10), 1, None).validate().is_ok());
+        assert_eq!(spawn(DepthTable::constant(10), 0, None).validate(), Err("min_depth starts at 1".into()));
+        assert_eq!(spawn(DepthTable::constant(10), 3, Some(2)).validate(),
+                   Err("max_depth 2 is less than min_depth 3".into()));
+        assert_eq!(spawn(DepthTable { steps: vec![] }, 1, None).validate(),
+                   Err("weight: a depth table needs at least one step".into()));
+    }
+
+    #[test]
+    fn depth_tables_hold_a_value_down_to_the_next_step() {
+        let table: DepthTable = serde_json::from_str("[[2, 20], [4, 30], [7, 60]]").unwrap();
+        assert_eq!(table.at(1), 0);
+        assert_eq!(table.at(2), 20);
+        assert_eq!(table.at(3), 20);
+        assert_eq!(table.at(4), 30);
+        assert_eq!(table.at(6), 30);
+        assert_eq!(table.at(7), 60);
+        assert_eq!(table.at(100), 60);
+    }
+
+    #[test]
+    fn a_plain_number_holds_at_every_depth() {
+        let table: DepthTable = serde_json::from_str("15").unwrap();
+        assert_eq!(table, DepthTable::constant(15));
+        assert_eq!(table.at(1), 15);
+        assert_eq!(table.at(50), 15);
+    }
+
+    #[test]
+    fn depth_tables_must_start_at_a_level_and_go_down() {
+        let table = |text: &str| serde_json::from_str::<DepthTable>(text).unwrap().validate();
+        assert!(table("[[1, 3], [4, 5]]").is_ok());
+        assert_eq!(table("[]"), Err("a depth table needs at least one step".into()));
+        assert_eq!(table("[[0, 3]]"), Err("the depths of a depth table start at 1".into()));
+        assert!(table("[[3, 3], [3, 5]]").is_err());
+        assert!(table("[[4, 3], [2, 5]]").is_err());
+        assert!(serde_json::from_str::<DepthTable>("\"many\"").is_err());
+    }
+
+    #[test]
+    fn distributions_leave_out_kinds_that_do_not_turn_up() {
+        let (orc, troll, ghost) = ("orc".to_string(), "troll".to_string(), "ghost".to_string());
+        let chances = Distribution::chances(vec![(&orc, 30), (&troll, 10), (&ghost, 0)]);
+        assert_eq!(chances, vec![(orc, 0.75), (troll, 0.25)]);
+    }
+
+    #[test]
+    fn the_total_weight_is_checked_wherever_it_changes() {
+        let half = u32::MAX / 2 + 1;
+        let shallow = spawn(DepthTable::constant(half), 1, Some(3));
+        let deep = spawn(DepthTable::constant(half), 4, None);
+        assert!(check_total_weight(&[&shallow, &deep]).is_ok());
+
+        let growing = spawn(DepthTable { steps: vec![(1, 1), (3, half)] }, 1, None);
+        assert_eq!(check_total_weight(&[&shallow, &growing]),
+                   Err(format!("the weights add up to more than {} at depth 3", u32::MAX)));
+    }
+
+    #[test]
+    fn chances_can_be_taken_of_heavy_weights() {
+        let (orc, troll) = ("orc".to_string(), "troll".to_string());
+        let chances = Distribution::chances(vec![(&orc, u32::MAX), (&troll, u32::MAX)]);
+        assert_eq!(chances, vec![(orc, 0.5), (troll, 0.5)]);
+    }
+
+    #[test]
+    fn rooms_can_not_be_overfilled() {
+        let counts = |max_monsters: &str| SpawnCounts {
+            max_monsters: serde_json::from_str(max_monsters).unwrap(),
+            max_items: DepthTable::constant(2),
+        };
+        assert!(counts("[[1, 3], [5, 20]]").validate().is_ok());
+        assert_eq!(counts("[[1, 3], [5, 21]]").validate(), Err("max_monsters: at most 20 per room".into()));
+        assert_eq!(counts("4294967295").validate(), Err("max_monsters: at most 20 per room".into()));
+        assert_eq!(counts("[]").validate(), Err("max_monsters: a depth table needs at least one step".into()));
+    }
+}